@@ -3,3 +3,29 @@ use crate::{sys::syscall::number::Syscall, syscall};
 pub fn sleep(seconds: f64) {
     let _ = unsafe { syscall!(Syscall::Sleep, usize::try_from(seconds.to_bits()).unwrap()) };
 }
+
+pub fn exit(code: usize) -> ! {
+    let _ = unsafe { syscall!(Syscall::Exit, code) };
+
+    unreachable!("exit syscall returned");
+}
+
+/// Returns the child's process id in the parent and 0 in the child.
+#[must_use]
+pub fn fork() -> Option<usize> {
+    let pid = unsafe { syscall!(Syscall::Fork) };
+
+    (pid != usize::MAX).then_some(pid)
+}
+
+pub fn shutdown() -> ! {
+    let _ = unsafe { syscall!(Syscall::Shutdown) };
+
+    unreachable!("shutdown syscall returned");
+}
+
+pub fn reboot() -> ! {
+    let _ = unsafe { syscall!(Syscall::Reboot) };
+
+    unreachable!("reboot syscall returned");
+}
@@ -1,5 +1,6 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![feature(allocator_api)]
 #![feature(naked_functions)]
 
@@ -20,18 +21,28 @@ pub fn init(boot_info: &'static mut BootInfo) {
     sys::gdt::init();
     sys::idt::init();
 
+    sys::cpu::init();
+
+    sys::memory::init(
+        boot_info.physical_memory_offset.into_option().unwrap(),
+        &boot_info.memory_regions,
+    );
+    sys::gdt::init_stacks();
+
+    sys::acpi::init(boot_info.rsdp_addr.into_option());
+
     sys::pic::init();
 
     sys::time::init();
+    sys::clocksource::init();
+    sys::timer::init();
     sys::serial::init();
     sys::task::keyboard::init();
 
-    sys::memory::init(
-        boot_info.physical_memory_offset.into_option().unwrap(),
-        &boot_info.memory_regions,
-    );
     sys::clock::init();
-    sys::cpu::init();
+
+    sys::smp::init();
+    sys::thread::init();
 
     sys::ata::init();
 
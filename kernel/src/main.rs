@@ -5,7 +5,10 @@ use bootloader_api::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use kernel::{
     println,
-    sys::task::{executor::Executor, keyboard, Task},
+    sys::{
+        task::{executor::Executor, keyboard, Priority, Task},
+        thread,
+    },
     BOOTLOADER_CONFIG,
 };
 
@@ -21,11 +24,17 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     println!("by skycloudd");
     println!();
 
-    let mut executor = Executor::new();
+    let executor = Executor::new();
 
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::with_priority(
+        "keyboard",
+        Priority::Interactive,
+        keyboard::print_keypresses(),
+    ));
 
-    executor.run();
+    thread::spawn("executor", move || executor.run());
+
+    thread::exit();
 }
 
 #[panic_handler]
@@ -0,0 +1,54 @@
+use super::{field, find_table, GenericAddress};
+use x86_64::PhysAddr;
+
+const RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+/// The power management registers from the fixed ACPI description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century: u8,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+#[must_use]
+pub fn parse() -> Option<Fadt> {
+    let table = find_table(b"FACP")?;
+
+    let flags = field(table, 112).unwrap_or(0);
+
+    let dsdt = field::<u64>(table, 140)
+        .filter(|&addr| addr != 0)
+        .or_else(|| field::<u32>(table, 40).map(u64::from))?;
+
+    let reset_register = field::<GenericAddress>(table, 116)
+        .filter(|reset| flags & RESET_REG_SUPPORTED != 0 && reset.address != 0);
+
+    Some(Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        sci_interrupt: field(table, 46)?,
+        smi_command_port: field(table, 48)?,
+        acpi_enable: field(table, 52)?,
+        acpi_disable: field(table, 53)?,
+        pm1a_event_block: field(table, 56)?,
+        pm1b_event_block: field(table, 60)?,
+        pm1a_control_block: field(table, 64)?,
+        pm1b_control_block: field(table, 68)?,
+        pm_timer_block: field(table, 76)?,
+        century: field(table, 108).unwrap_or(0),
+        flags,
+        reset_register,
+        reset_value: field(table, 128).unwrap_or(0),
+    })
+}
@@ -0,0 +1,26 @@
+use super::{field, find_table, GenericAddress};
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub base: PhysAddr,
+    pub number: u8,
+    pub minimum_tick: u16,
+}
+
+#[must_use]
+pub fn parse() -> Option<Hpet> {
+    let table = find_table(b"HPET")?;
+
+    let base = field::<GenericAddress>(table, 40)?;
+
+    if base.address_space != GenericAddress::SYSTEM_MEMORY {
+        return None;
+    }
+
+    Some(Hpet {
+        base: PhysAddr::new(base.address),
+        number: field(table, 52)?,
+        minimum_tick: field(table, 53)?,
+    })
+}
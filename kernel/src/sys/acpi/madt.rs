@@ -0,0 +1,107 @@
+use super::{find_table, read, SdtHeader, HEADER_SIZE};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// A legacy IRQ that is wired to a different global system interrupt, or
+/// with a different polarity or trigger mode than ISA defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Where legacy `irq` ends up, following any override.
+    #[must_use]
+    pub fn irq_override(&self, irq: u8) -> Option<&InterruptOverride> {
+        self.overrides.iter().find(|o| o.irq == irq)
+    }
+}
+
+#[must_use]
+pub fn parse() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+
+    let header = unsafe { read::<SdtHeader>(table) };
+    let local_apic_address = unsafe { read::<u32>(table + HEADER_SIZE as u64) };
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(local_apic_address)),
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    // entries follow the local APIC address and flags
+    let end = table + u64::from(header.length);
+    let mut entry = table + HEADER_SIZE as u64 + 8;
+
+    while entry + 2u64 <= end {
+        let kind = unsafe { read::<u8>(entry) };
+        let length = unsafe { read::<u8>(entry + 1u64) };
+
+        if length < 2 {
+            break;
+        }
+
+        match kind {
+            PROCESSOR_LOCAL_APIC => madt.processors.push(Processor {
+                processor_id: unsafe { read(entry + 2u64) },
+                apic_id: unsafe { read(entry + 3u64) },
+                enabled: unsafe { read::<u32>(entry + 4u64) } & 1 != 0,
+            }),
+            IO_APIC => madt.io_apics.push(IoApic {
+                id: unsafe { read(entry + 2u64) },
+                address: PhysAddr::new(u64::from(unsafe { read::<u32>(entry + 4u64) })),
+                gsi_base: unsafe { read(entry + 8u64) },
+            }),
+            INTERRUPT_SOURCE_OVERRIDE => {
+                let flags = unsafe { read::<u16>(entry + 8u64) };
+
+                madt.overrides.push(InterruptOverride {
+                    irq: unsafe { read(entry + 3u64) },
+                    gsi: unsafe { read(entry + 4u64) },
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = PhysAddr::new(unsafe { read(entry + 4u64) });
+            }
+            _ => {}
+        }
+
+        entry += u64::from(length);
+    }
+
+    Some(madt)
+}
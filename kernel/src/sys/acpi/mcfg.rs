@@ -0,0 +1,36 @@
+use super::{field, find_table, read, SdtHeader};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const ENTRIES_OFFSET: usize = 44;
+const ENTRY_SIZE: usize = 16;
+
+/// A PCI Express enhanced configuration space window covering a range of buses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamRegion {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[must_use]
+pub fn parse() -> Option<Vec<EcamRegion>> {
+    let table = find_table(b"MCFG")?;
+
+    let length = unsafe { read::<SdtHeader>(table) }.length as usize;
+    let entries = length.saturating_sub(ENTRIES_OFFSET) / ENTRY_SIZE;
+
+    (0..entries)
+        .map(|i| {
+            let entry = ENTRIES_OFFSET + i * ENTRY_SIZE;
+
+            Some(EcamRegion {
+                base: PhysAddr::new(field(table, entry)?),
+                segment: field(table, entry + 8)?,
+                start_bus: field(table, entry + 10)?,
+                end_bus: field(table, entry + 11)?,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,232 @@
+use super::memory::phys_to_virt;
+use crate::log;
+use alloc::{string::String, vec::Vec};
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+/// Physical addresses of every valid table listed by the root table.
+static TABLES: OnceCell<Vec<PhysAddr>> = OnceCell::uninit();
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Bytes covered by the checksum of a revision 0 RSDP.
+const RSDP_V1_LENGTH: usize = 20;
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every system description table starts with.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<SdtHeader>();
+
+/// Where a register lives, as described by the ACPI generic address
+/// structure.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIGURATION: u8 = 2;
+}
+
+pub fn init(rsdp_addr: Option<u64>) {
+    let Some(rsdp_addr) = rsdp_addr else {
+        log!("acpi not available");
+        return;
+    };
+
+    let Some(tables) = (unsafe { load_tables(PhysAddr::new(rsdp_addr)) }) else {
+        log!("acpi rsdp at {rsdp_addr:#x} is invalid");
+        return;
+    };
+
+    TABLES.init_once(|| tables);
+
+    log_summary();
+}
+
+/// Finds the root table through the RSDP and collects the tables it lists
+/// whose checksums are valid.
+///
+/// # Safety
+///
+/// `rsdp_addr` must be the physical address the bootloader reported.
+unsafe fn load_tables(rsdp_addr: PhysAddr) -> Option<Vec<PhysAddr>> {
+    let rsdp = unsafe { read::<Rsdp>(rsdp_addr) };
+
+    if rsdp.signature != *RSDP_SIGNATURE || !checksum_valid(rsdp_addr, RSDP_V1_LENGTH) {
+        return None;
+    }
+
+    // revision 2 and up point at the XSDT, with 64 bit entries
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        if !checksum_valid(rsdp_addr, rsdp.length as usize) {
+            return None;
+        }
+
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+
+    let header = unsafe { read::<SdtHeader>(root) };
+
+    if !table_valid(root) {
+        return None;
+    }
+
+    let entries = (header.length as usize).saturating_sub(HEADER_SIZE) / entry_size;
+
+    let tables = (0..entries)
+        .map(|i| {
+            let entry = root + (HEADER_SIZE + i * entry_size) as u64;
+
+            if entry_size == 8 {
+                PhysAddr::new(unsafe { read::<u64>(entry) })
+            } else {
+                PhysAddr::new(u64::from(unsafe { read::<u32>(entry) }))
+            }
+        })
+        .filter(|&table| {
+            let valid = table_valid(table);
+
+            if !valid {
+                log!("acpi table at {table:?} has a bad checksum");
+            }
+
+            valid
+        })
+        .collect();
+
+    Some(tables)
+}
+
+fn log_summary() {
+    let signatures = TABLES
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|&table| {
+            let signature = unsafe { read::<SdtHeader>(table) }.signature;
+
+            String::from_utf8_lossy(&signature).into_owned()
+        })
+        .collect::<Vec<_>>();
+
+    log!("acpi tables {}", signatures.join(" "));
+
+    if let Some(madt) = madt::parse() {
+        let enabled = madt.processors.iter().filter(|p| p.enabled).count();
+
+        log!(
+            "acpi {enabled} cpus, {} io apics, {} irq overrides",
+            madt.io_apics.len(),
+            madt.overrides.len()
+        );
+    }
+
+    if let Some(fadt) = fadt::parse() {
+        log!(
+            "acpi sci irq {}, pm1a control {:#x}, pm timer {:#x}, reset {}",
+            fadt.sci_interrupt,
+            fadt.pm1a_control_block,
+            fadt.pm_timer_block,
+            if fadt.reset_register.is_some() {
+                "supported"
+            } else {
+                "unsupported"
+            }
+        );
+    }
+
+    if let Some(hpet) = hpet::parse() {
+        log!("acpi hpet at {:?}", hpet.base);
+    }
+
+    for region in mcfg::parse().into_iter().flatten() {
+        log!(
+            "acpi pcie ecam at {:?}, segment {}, buses {}..={}",
+            region.base,
+            region.segment,
+            region.start_bus,
+            region.end_bus
+        );
+    }
+}
+
+/// The physical address of the first table with this signature.
+#[must_use]
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    TABLES
+        .get()?
+        .iter()
+        .copied()
+        .find(|&table| unsafe { read::<SdtHeader>(table) }.signature == *signature)
+}
+
+fn table_valid(table: PhysAddr) -> bool {
+    let length = unsafe { read::<SdtHeader>(table) }.length as usize;
+
+    length >= HEADER_SIZE && checksum_valid(table, length)
+}
+
+/// ACPI structures are valid when all of their bytes add up to zero.
+fn checksum_valid(addr: PhysAddr, length: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), length) };
+
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Reads a possibly unaligned value out of physical memory.
+///
+/// # Safety
+///
+/// `addr` must point at a valid `T` in firmware tables.
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { phys_to_virt(addr).as_ptr::<T>().read_unaligned() }
+}
+
+/// Reads the field at `offset` of a table, or `None` for fields an older
+/// revision of the table does not have.
+fn field<T: Copy>(table: PhysAddr, offset: usize) -> Option<T> {
+    let length = unsafe { read::<SdtHeader>(table) }.length as usize;
+
+    (offset + core::mem::size_of::<T>() <= length).then(|| unsafe { read(table + offset as u64) })
+}
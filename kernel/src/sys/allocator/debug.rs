@@ -0,0 +1,122 @@
+//! Heap checking for the `debug-alloc` feature.
+//!
+//! Every allocation is padded with guard bytes on both sides. Blocks on the
+//! size class free lists are tagged with [`FREE_MAGIC`] and filled with
+//! [`POISON_BYTE`], which catches double frees and writes after free.
+
+use super::fixed_size_block::BLOCK_SIZES;
+use core::{alloc::Layout, fmt};
+
+const GUARD_SIZE: usize = 16;
+const GUARD_BYTE: u8 = 0xFD;
+const POISON_BYTE: u8 = 0xDD;
+
+// stored just after the free list node, where live blocks have guard bytes
+const FREE_MAGIC: u64 = 0xDEAD_F4EE_DEAD_F4EE;
+const FREE_MAGIC_OFFSET: usize = 8;
+const POISON_OFFSET: usize = FREE_MAGIC_OFFSET + core::mem::size_of::<u64>();
+
+/// The layout actually requested from the heap for `layout`.
+pub fn padded(layout: &Layout) -> Layout {
+    Layout::from_size_align(
+        front_size(layout) + layout.size() + GUARD_SIZE,
+        layout.align().max(GUARD_SIZE),
+    )
+    .unwrap()
+}
+
+/// Writes the guard bytes around a freshly allocated block and returns the
+/// pointer handed to the caller.
+pub unsafe fn guard(block: *mut u8, layout: &Layout) -> *mut u8 {
+    let front = front_size(layout);
+    let ptr = block.wrapping_add(front);
+    let back = ptr.wrapping_add(layout.size());
+
+    unsafe { block.write_bytes(GUARD_BYTE, front) };
+    unsafe { back.write_bytes(GUARD_BYTE, GUARD_SIZE) };
+
+    ptr
+}
+
+/// Checks the guard bytes of an allocation being freed and returns the
+/// start of its block.
+pub unsafe fn check(ptr: *mut u8, layout: &Layout, index: Option<usize>) -> *mut u8 {
+    let front = front_size(layout);
+    let block = ptr.wrapping_sub(front);
+
+    assert!(
+        index.is_none() || unsafe { read_magic(block) } != FREE_MAGIC,
+        "double free of {ptr:p} in the {}",
+        SizeClass(index)
+    );
+
+    let front_guard = unsafe { core::slice::from_raw_parts(block, front) };
+    assert!(
+        front_guard.iter().all(|&b| b == GUARD_BYTE),
+        "heap corruption before {ptr:p} in the {} (underflow or double free)",
+        SizeClass(index)
+    );
+
+    let back = ptr.wrapping_add(layout.size());
+    let back_guard = unsafe { core::slice::from_raw_parts(back, GUARD_SIZE) };
+    assert!(
+        back_guard.iter().all(|&b| b == GUARD_BYTE),
+        "heap corruption after {ptr:p} in the {} ({} byte allocation overflowed)",
+        SizeClass(index),
+        layout.size()
+    );
+
+    block
+}
+
+/// Tags a block that is going onto a free list and poisons its contents.
+pub unsafe fn poison(block: *mut u8, index: usize) {
+    #[allow(clippy::cast_ptr_alignment)]
+    // only ever accessed unaligned
+    let magic = block.wrapping_add(FREE_MAGIC_OFFSET).cast::<u64>();
+    let poisoned = block.wrapping_add(POISON_OFFSET);
+
+    unsafe { magic.write_unaligned(FREE_MAGIC) };
+    unsafe { poisoned.write_bytes(POISON_BYTE, BLOCK_SIZES[index] - POISON_OFFSET) };
+}
+
+/// Poisons the caller-visible part of a fallback allocation being freed.
+pub const unsafe fn poison_large(ptr: *mut u8, layout: &Layout) {
+    unsafe { ptr.write_bytes(POISON_BYTE, layout.size()) };
+}
+
+/// Verifies a block taken off a free list was not written to while free.
+pub unsafe fn check_poison(block: *mut u8, index: usize) {
+    let poisoned = block.wrapping_add(POISON_OFFSET);
+    let poisoned =
+        unsafe { core::slice::from_raw_parts(poisoned, BLOCK_SIZES[index] - POISON_OFFSET) };
+
+    assert!(
+        unsafe { read_magic(block) } == FREE_MAGIC && poisoned.iter().all(|&b| b == POISON_BYTE),
+        "use after free of {block:p} detected in the {}",
+        SizeClass(Some(index))
+    );
+}
+
+const unsafe fn read_magic(block: *mut u8) -> u64 {
+    #[allow(clippy::cast_ptr_alignment)]
+    // only ever accessed unaligned
+    let magic = block.wrapping_add(FREE_MAGIC_OFFSET).cast::<u64>();
+
+    unsafe { magic.read_unaligned() }
+}
+
+fn front_size(layout: &Layout) -> usize {
+    layout.align().max(GUARD_SIZE)
+}
+
+struct SizeClass(Option<usize>);
+
+impl fmt::Display for SizeClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(index) => write!(f, "{} byte size class", BLOCK_SIZES[index]),
+            None => write!(f, "fallback heap"),
+        }
+    }
+}
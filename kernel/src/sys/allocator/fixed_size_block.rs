@@ -1,18 +1,43 @@
-use super::Locked;
+#[cfg(feature = "debug-alloc")]
+use super::debug;
+use super::{AllocatorStats, HeapUsage, Locked, SizeClassStats, HEAP_GROWTH_STEP};
 use core::{
     alloc::{GlobalAlloc, Layout},
     ptr::NonNull,
 };
+use x86_64::{instructions::interrupts, VirtAddr};
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+#[derive(Clone, Copy)]
+struct Counters {
+    allocated: usize,
+    free: usize,
+    peak: usize,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            allocated: 0,
+            free: 0,
+            peak: 0,
+        }
+    }
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    counters: [Counters; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    mapped: usize,
+    large_allocations: usize,
+    bytes_in_use: usize,
+    peak_bytes: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -21,32 +46,157 @@ impl FixedSizeBlockAllocator {
 
         Self {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
+            counters: [Counters::new(); BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            mapped: 0,
+            large_allocations: 0,
+            bytes_in_use: 0,
+            peak_bytes: 0,
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: *mut u8, heap_size: usize) {
         unsafe { self.fallback_allocator.init(heap_start, heap_size) };
+
+        self.mapped = heap_size;
+    }
+
+    pub fn usage(&self) -> HeapUsage {
+        HeapUsage {
+            mapped: self.mapped,
+            used: self.fallback_allocator.used(),
+            free: self.fallback_allocator.free(),
+            limit: super::heap_limit(),
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let mut classes = [SizeClassStats::default(); BLOCK_SIZES.len()];
+
+        for ((class, counters), &block_size) in
+            classes.iter_mut().zip(&self.counters).zip(BLOCK_SIZES)
+        {
+            *class = SizeClassStats {
+                block_size,
+                allocated: counters.allocated,
+                free: counters.free,
+                peak: counters.peak,
+            };
+        }
+
+        AllocatorStats {
+            classes,
+            large_allocations: self.large_allocations,
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes: self.peak_bytes,
+            heap: self.usage(),
+        }
+    }
+
+    fn record_alloc(&mut self, index: Option<usize>, size: usize) {
+        if let Some(index) = index {
+            let counters = &mut self.counters[index];
+
+            counters.allocated += 1;
+            counters.peak = counters.peak.max(counters.allocated);
+        } else {
+            self.large_allocations += 1;
+        }
+
+        self.bytes_in_use += size;
+        self.peak_bytes = self.peak_bytes.max(self.bytes_in_use);
+    }
+
+    fn record_dealloc(&mut self, index: Option<usize>, size: usize) {
+        if let Some(index) = index {
+            let counters = &mut self.counters[index];
+
+            counters.allocated -= 1;
+            counters.free += 1;
+        } else {
+            self.large_allocations -= 1;
+        }
+
+        self.bytes_in_use -= size;
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        if !self.grow(layout) {
+            return core::ptr::null_mut();
+        }
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(()) => core::ptr::null_mut(),
         }
     }
+
+    fn grow(&mut self, layout: Layout) -> bool {
+        let available = super::heap_limit().saturating_sub(self.mapped);
+
+        let wanted = (layout.size() + layout.align())
+            .max(HEAP_GROWTH_STEP)
+            .min(available);
+
+        if wanted < layout.size() {
+            return false;
+        }
+
+        let top = VirtAddr::from_ptr(self.fallback_allocator.bottom()) + self.mapped as u64;
+
+        let mapped = super::grow_heap(top, wanted);
+
+        if mapped == 0 {
+            return false;
+        }
+
+        unsafe { self.fallback_allocator.extend(mapped) };
+
+        self.mapped += mapped;
+
+        true
+    }
 }
 
+// Interrupts are disabled while the lock is held, so interrupt handlers may
+// allocate too.
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| unsafe { self.allocate(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| unsafe { self.deallocate(ptr, layout) });
+    }
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
+        #[cfg(feature = "debug-alloc")]
+        let (layout, requested) = (debug::padded(&layout), layout);
+
+        let index = list_index(&layout);
+
+        let ptr = match index {
             Some(index) => {
                 if let Some(node) = allocator.list_heads[index].take() {
                     allocator.list_heads[index] = node.next.take();
+                    allocator.counters[index].free -= 1;
 
-                    core::ptr::from_mut::<ListNode>(node).cast::<u8>()
+                    let block = core::ptr::from_mut::<ListNode>(node).cast::<u8>();
+
+                    #[cfg(feature = "debug-alloc")]
+                    unsafe {
+                        debug::check_poison(block, index);
+                    }
+
+                    block
                 } else {
                     let block_size = BLOCK_SIZES[index];
 
@@ -59,13 +209,41 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+
+        if ptr.is_null() {
+            return ptr;
         }
+
+        allocator.record_alloc(index, allocated_size(index, &layout));
+
+        #[cfg(feature = "debug-alloc")]
+        let ptr = unsafe { debug::guard(ptr, &requested) };
+
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
 
-        if let Some(index) = list_index(&layout) {
+        #[cfg(feature = "debug-alloc")]
+        let (ptr, layout) = {
+            let padded = debug::padded(&layout);
+
+            let block = unsafe { debug::check(ptr, &layout, list_index(&padded)) };
+
+            if list_index(&padded).is_none() {
+                unsafe { debug::poison_large(ptr, &layout) };
+            }
+
+            (block, padded)
+        };
+
+        let index = list_index(&layout);
+
+        allocator.record_dealloc(index, allocated_size(index, &layout));
+
+        if let Some(index) = index {
             let new_node = ListNode {
                 next: allocator.list_heads[index].take(),
             };
@@ -73,6 +251,11 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             assert!(core::mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
             assert!(core::mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
 
+            #[cfg(feature = "debug-alloc")]
+            unsafe {
+                debug::poison(ptr, index);
+            }
+
             #[allow(clippy::cast_ptr_alignment)]
             // SAFETY: pointer alignment is checked above
             let new_node_ptr = ptr.cast::<ListNode>();
@@ -93,3 +276,7 @@ fn list_index(layout: &Layout) -> Option<usize> {
 
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
+
+fn allocated_size(index: Option<usize>, layout: &Layout) -> usize {
+    index.map_or(layout.size(), |index| BLOCK_SIZES[index])
+}
@@ -1,43 +1,40 @@
-use fixed_size_block::FixedSizeBlockAllocator;
+use super::memory::vmm;
+use crate::log;
+use core::{
+    alloc::Layout,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use fixed_size_block::{FixedSizeBlockAllocator, BLOCK_SIZES};
 use spin::{Mutex, MutexGuard};
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    instructions::interrupts,
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
+#[cfg(feature = "debug-alloc")]
+mod debug;
 mod fixed_size_block;
+pub mod slab;
 
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 pub const HEAP_START: u64 = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1u64;
-
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
 
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() }
-    }
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    vmm::map_range(VirtAddr::new(HEAP_START), HEAP_SIZE as u64, HEAP_FLAGS)?;
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
@@ -46,6 +43,117 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Sets the size the heap may grow to; it never shrinks below what is
+/// already mapped.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.max(HEAP_SIZE), Ordering::Relaxed);
+}
+
+#[must_use]
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+#[must_use]
+pub fn usage() -> HeapUsage {
+    interrupts::without_interrupts(|| ALLOCATOR.lock().usage())
+}
+
+#[must_use]
+pub fn stats() -> AllocatorStats {
+    interrupts::without_interrupts(|| ALLOCATOR.lock().stats())
+}
+
+pub fn log_stats() {
+    let stats = stats();
+
+    for class in stats.classes.iter().filter(|class| class.peak > 0) {
+        log!("{}", class);
+    }
+
+    log!(
+        "{} large allocations, {} bytes in use, {} bytes peak",
+        stats.large_allocations,
+        stats.bytes_in_use,
+        stats.peak_bytes
+    );
+    log!("{}", stats.heap);
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    log!(
+        "out of memory allocating {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+
+    log_stats();
+
+    panic!("allocation error: {:?}", layout);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub allocated: usize,
+    pub free: usize,
+    pub peak: usize,
+}
+
+impl fmt::Display for SizeClassStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4} B blocks: {} allocated, {} free, {} peak",
+            self.block_size, self.allocated, self.free, self.peak
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub classes: [SizeClassStats; BLOCK_SIZES.len()],
+    pub large_allocations: usize,
+    pub bytes_in_use: usize,
+    pub peak_bytes: usize,
+    pub heap: HeapUsage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub mapped: usize,
+    pub used: usize,
+    pub free: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for HeapUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap {} KiB used, {} KiB free, {} KiB mapped of {} KiB",
+            self.used / 1024,
+            self.free / 1024,
+            self.mapped / 1024,
+            self.limit / 1024
+        )
+    }
+}
+
+/// Maps up to `size` bytes of fresh pages at `start` and returns how many
+/// bytes were actually mapped before running out of frames.
+#[allow(clippy::cast_possible_truncation)]
+fn grow_heap(start: VirtAddr, size: usize) -> usize {
+    let pages = (size as u64).div_ceil(vmm::PAGE_SIZE);
+
+    (0..pages)
+        .map(|i| start + i * vmm::PAGE_SIZE)
+        .take_while(|&page| vmm::map_range(page, vmm::PAGE_SIZE, HEAP_FLAGS).is_ok())
+        .count()
+        * vmm::PAGE_SIZE as usize
+}
+
 struct Locked<T> {
     inner: Mutex<T>,
 }
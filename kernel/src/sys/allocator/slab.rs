@@ -0,0 +1,325 @@
+use crate::sys::memory::{
+    frame::{self, FRAME_SIZE},
+    phys_to_virt,
+};
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    fmt,
+    marker::PhantomData,
+    ptr::NonNull,
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, structures::paging::PhysFrame, PhysAddr};
+
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+#[allow(clippy::cast_possible_truncation)]
+const SLAB_UNIT: usize = FRAME_SIZE as usize;
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Lives at the start of every slab, followed by the objects themselves.
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+struct State {
+    partial: Option<NonNull<Slab>>,
+    slabs: usize,
+    allocated: usize,
+}
+
+unsafe impl Send for State {}
+
+/// A cache of equally sized objects carved out of whole pages taken straight
+/// from the frame allocator, bypassing the global heap.
+///
+/// `&SlabCache<T>` implements [`Allocator`], so objects can be placed in it
+/// with `Box::new_in(value, &CACHE)`.
+pub struct SlabCache<T> {
+    name: &'static str,
+    state: Mutex<State>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SlabCache<T> {
+    const OBJECT_ALIGN: usize = max(
+        core::mem::align_of::<T>(),
+        core::mem::align_of::<FreeObject>(),
+    );
+
+    const OBJECT_SIZE: usize = max(
+        core::mem::size_of::<T>(),
+        core::mem::size_of::<FreeObject>(),
+    )
+    .next_multiple_of(Self::OBJECT_ALIGN);
+
+    const OBJECTS_OFFSET: usize = core::mem::size_of::<Slab>().next_multiple_of(Self::OBJECT_ALIGN);
+
+    const SLAB_FRAMES: usize = {
+        let mut frames = 1;
+
+        while Self::objects_in(frames) < MIN_OBJECTS_PER_SLAB {
+            frames *= 2;
+        }
+
+        frames
+    };
+
+    const SLAB_SIZE: usize = Self::SLAB_FRAMES * SLAB_UNIT;
+
+    const OBJECTS_PER_SLAB: usize = Self::objects_in(Self::SLAB_FRAMES);
+
+    #[must_use]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: Mutex::new(State {
+                partial: None,
+                slabs: 0,
+                allocated: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns uninitialized memory for one `T`, or `None` when no frames
+    /// are left for a new slab.
+    pub fn allocate(&self) -> Option<NonNull<T>> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            let slab = if let Some(slab) = state.partial {
+                slab
+            } else {
+                let slab = Self::new_slab()?;
+
+                state.push(slab);
+                state.slabs += 1;
+
+                slab
+            };
+
+            let slab_ref = unsafe { &mut *slab.as_ptr() };
+
+            let object = slab_ref.free.expect("partial slab without free objects");
+
+            slab_ref.free = unsafe { object.as_ref().next };
+            slab_ref.in_use += 1;
+            state.allocated += 1;
+
+            if slab_ref.free.is_none() {
+                state.remove(slab);
+            }
+
+            Some(object.cast())
+        })
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`Self::allocate`] on this cache and
+    /// not freed since.
+    pub unsafe fn deallocate(&self, ptr: NonNull<T>) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            let slab = Self::slab_of(ptr);
+            let slab_ref = unsafe { &mut *slab.as_ptr() };
+
+            let was_full = slab_ref.free.is_none();
+
+            let object = ptr.cast::<FreeObject>();
+            let next = slab_ref.free;
+
+            unsafe { object.write(FreeObject { next }) };
+
+            slab_ref.free = Some(object);
+            slab_ref.in_use -= 1;
+            state.allocated -= 1;
+
+            if was_full {
+                state.push(slab);
+            }
+
+            // keep the last partial slab around so a single object bouncing
+            // in and out does not hit the frame allocator every time
+            if slab_ref.in_use == 0 && (slab_ref.prev.is_some() || slab_ref.next.is_some()) {
+                state.remove(slab);
+                state.slabs -= 1;
+
+                Self::free_slab(slab);
+            }
+        });
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> SlabStats {
+        interrupts::without_interrupts(|| {
+            let state = self.state.lock();
+
+            SlabStats {
+                name: self.name,
+                object_size: Self::OBJECT_SIZE,
+                objects_per_slab: Self::OBJECTS_PER_SLAB,
+                slabs: state.slabs,
+                allocated: state.allocated,
+            }
+        })
+    }
+
+    const fn objects_in(frames: usize) -> usize {
+        (frames * SLAB_UNIT - Self::OBJECTS_OFFSET) / Self::OBJECT_SIZE
+    }
+
+    fn new_slab() -> Option<NonNull<Slab>> {
+        assert!(
+            Self::OBJECT_ALIGN <= SLAB_UNIT,
+            "slab objects cannot be aligned beyond a frame"
+        );
+
+        let start = frame::allocate_contiguous(Self::SLAB_FRAMES, Self::SLAB_FRAMES)?;
+
+        let base = phys_to_virt(start.start_address()).as_mut_ptr::<u8>();
+
+        let mut free = None;
+
+        for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+            #[allow(clippy::cast_ptr_alignment)]
+            // SAFETY: objects are laid out at multiples of their alignment
+            let object = base
+                .wrapping_add(Self::OBJECTS_OFFSET + i * Self::OBJECT_SIZE)
+                .cast::<FreeObject>();
+
+            unsafe { object.write(FreeObject { next: free }) };
+
+            free = NonNull::new(object);
+        }
+
+        #[allow(clippy::cast_ptr_alignment)]
+        // SAFETY: slabs start on a frame boundary
+        let slab = base.cast::<Slab>();
+
+        unsafe {
+            slab.write(Slab {
+                prev: None,
+                next: None,
+                free,
+                in_use: 0,
+            });
+        }
+
+        NonNull::new(slab)
+    }
+
+    fn free_slab(slab: NonNull<Slab>) {
+        let phys = slab.as_ptr() as u64 - phys_to_virt(PhysAddr::zero()).as_u64();
+
+        frame::deallocate_contiguous(
+            PhysFrame::containing_address(PhysAddr::new(phys)),
+            Self::SLAB_FRAMES,
+        );
+    }
+
+    /// Slabs are aligned to their size in physical memory, so the owning slab
+    /// is found by rounding the object's physical address down.
+    fn slab_of(ptr: NonNull<T>) -> NonNull<Slab> {
+        let phys = ptr.as_ptr() as u64 - phys_to_virt(PhysAddr::zero()).as_u64();
+        let slab_phys = phys - phys % Self::SLAB_SIZE as u64;
+
+        NonNull::new(phys_to_virt(PhysAddr::new(slab_phys)).as_mut_ptr()).unwrap()
+    }
+}
+
+unsafe impl<T> Allocator for SlabCache<T> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > Self::OBJECT_SIZE || layout.align() > Self::OBJECT_ALIGN {
+            return Err(AllocError);
+        }
+
+        let object = Self::allocate(self).ok_or(AllocError)?;
+
+        Ok(NonNull::slice_from_raw_parts(
+            object.cast(),
+            Self::OBJECT_SIZE,
+        ))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        unsafe { Self::deallocate(self, ptr.cast()) };
+    }
+}
+
+impl State {
+    fn push(&mut self, slab: NonNull<Slab>) {
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+
+        slab_ref.prev = None;
+        slab_ref.next = self.partial;
+
+        if let Some(mut head) = self.partial {
+            unsafe { head.as_mut().prev = Some(slab) };
+        }
+
+        self.partial = Some(slab);
+    }
+
+    fn remove(&mut self, slab: NonNull<Slab>) {
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+
+        match slab_ref.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = slab_ref.next },
+            None => self.partial = slab_ref.next,
+        }
+
+        if let Some(mut next) = slab_ref.next {
+            unsafe { next.as_mut().prev = slab_ref.prev };
+        }
+
+        slab_ref.prev = None;
+        slab_ref.next = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub allocated: usize,
+}
+
+impl SlabStats {
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        self.slabs * self.objects_per_slab
+    }
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slab {}: {}/{} objects of {} B in {} slabs",
+            self.name,
+            self.allocated,
+            self.capacity(),
+            self.object_size,
+            self.slabs
+        )
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
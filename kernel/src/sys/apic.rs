@@ -0,0 +1,318 @@
+use super::{
+    acpi::madt::{self, InterruptOverride},
+    idt::interrupt_index,
+    memory::vmm,
+};
+use crate::log;
+use conquer_once::spin::OnceCell;
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x86_64::{instructions::interrupts, registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+pub const TIMER_VECTOR: u8 = 0xEF;
+pub const WAKEUP_VECTOR: u8 = 0xEE;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_SVR: u64 = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3E0;
+
+const TIMER_ONE_SHOT: u32 = 0;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_FIXED: u32 = 0x4000;
+const ICR_INIT: u32 = 0x4500;
+const ICR_STARTUP: u32 = 0x4600;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static LOCAL_APIC: OnceCell<VirtAddr> = OnceCell::uninit();
+static IO_APIC: OnceCell<Mutex<IoApic>> = OnceCell::uninit();
+
+/// Global system interrupt each legacy IRQ is wired to, if it is routed.
+static ROUTES: OnceCell<[Option<u32>; 16]> = OnceCell::uninit();
+
+/// The 8259 cascade, which never raises an interrupt of its own.
+const CASCADE_IRQ: u8 = 2;
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe { mmio_write(self.base + IOREGSEL, register) };
+        unsafe { mmio_read(self.base + IOWIN) }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe { mmio_write(self.base + IOREGSEL, register) };
+        unsafe { mmio_write(self.base + IOWIN, value) };
+    }
+
+    const fn redirection(&self, gsi: u32) -> u32 {
+        IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2
+    }
+}
+
+/// Switches interrupt delivery to the local APIC and IO-APIC, with every
+/// legacy IRQ routed but masked. Returns `false`, leaving the 8259 in
+/// charge, if there is no APIC or no MADT describing it.
+pub fn init() -> bool {
+    let has_apic = CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_apic());
+
+    if !has_apic {
+        return false;
+    }
+
+    let Some(madt) = madt::parse() else {
+        return false;
+    };
+
+    let Some(io_apic) = madt.io_apics.iter().min_by_key(|io_apic| io_apic.gsi_base) else {
+        return false;
+    };
+
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+
+    let local_apic = vmm::map_mmio(PhysAddr::new(base & 0x000F_FFFF_FFFF_F000), 0x400)
+        .expect("failed to map the local apic");
+    let io_apic_base = vmm::map_mmio(io_apic.address, 0x20).expect("failed to map the io apic");
+
+    LOCAL_APIC.init_once(|| local_apic);
+    IO_APIC.init_once(|| {
+        Mutex::new(IoApic {
+            base: io_apic_base,
+            gsi_base: io_apic.gsi_base,
+        })
+    });
+
+    enable_local_apic(local_apic);
+
+    let destination = id().unwrap();
+
+    let mut routes = [None; 16];
+
+    for (irq, route) in (0..16).zip(routes.iter_mut()) {
+        let irq_override = madt.irq_override(irq);
+
+        let gsi = irq_override.map_or_else(|| u32::from(irq), |o| o.gsi);
+
+        // an IRQ without an override of its own keeps its pin unless another
+        // IRQ was moved there, like the timer usually is onto the cascade's
+        let claimed =
+            irq_override.is_none() && madt.overrides.iter().any(|o| o.irq != irq && o.gsi == gsi);
+
+        if irq == CASCADE_IRQ || claimed {
+            continue;
+        }
+
+        route_irq(irq, gsi, irq_override, destination);
+
+        *route = Some(gsi);
+    }
+
+    ROUTES.init_once(|| routes);
+
+    ENABLED.store(true, Ordering::SeqCst);
+
+    log!(
+        "apic initialized, io apic {} at {:?}, {} overrides",
+        io_apic.id,
+        io_apic.address,
+        madt.overrides.len()
+    );
+
+    true
+}
+
+/// Enables the local APIC of an application processor, which shares its
+/// address with every other one.
+pub fn init_ap() {
+    enable_local_apic(*LOCAL_APIC.get().expect("apic not initialized"));
+}
+
+fn enable_local_apic(local_apic: VirtAddr) {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+
+    let base = unsafe { apic_base.read() };
+
+    unsafe { apic_base.write(base | APIC_BASE_ENABLE) };
+
+    unsafe { mmio_write(local_apic + LAPIC_TPR, 0) };
+    unsafe {
+        mmio_write(
+            local_apic + LAPIC_SVR,
+            u32::from(SPURIOUS_VECTOR) | LAPIC_SOFTWARE_ENABLE,
+        );
+    };
+}
+
+/// The APIC ID of the calling CPU.
+#[must_use]
+pub fn id() -> Option<u32> {
+    let &local_apic = LOCAL_APIC.get()?;
+
+    Some(unsafe { mmio_read(local_apic + LAPIC_ID) } >> 24)
+}
+
+/// Points the local timer of the calling CPU at [`TIMER_VECTOR`], either
+/// counting down from [`set_timer_count`] or firing at the TSC value given to
+/// [`set_tsc_deadline`]. It starts out disarmed.
+pub fn init_timer(tsc_deadline: bool) {
+    let local_apic = *LOCAL_APIC.get().expect("apic not initialized");
+
+    let mode = if tsc_deadline {
+        TIMER_TSC_DEADLINE
+    } else {
+        TIMER_ONE_SHOT
+    };
+
+    unsafe { mmio_write(local_apic + LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16) };
+    unsafe { mmio_write(local_apic + LAPIC_LVT_TIMER, u32::from(TIMER_VECTOR) | mode) };
+    unsafe { mmio_write(local_apic + LAPIC_TIMER_INITIAL_COUNT, 0) };
+}
+
+/// Starts the one-shot timer, which counts down at the bus frequency divided
+/// by 16. A count of 0 stops it.
+pub fn set_timer_count(count: u32) {
+    if let Some(&local_apic) = LOCAL_APIC.get() {
+        unsafe { mmio_write(local_apic + LAPIC_TIMER_INITIAL_COUNT, count) };
+    }
+}
+
+#[must_use]
+pub fn timer_count() -> u32 {
+    LOCAL_APIC.get().map_or(0, |&local_apic| unsafe {
+        mmio_read(local_apic + LAPIC_TIMER_CURRENT_COUNT)
+    })
+}
+
+/// Arms the timer in TSC-deadline mode; a deadline of 0 disarms it.
+pub fn set_tsc_deadline(tsc: u64) {
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc) };
+}
+
+/// Sends an INIT IPI, which resets the target CPU to wait for a startup IPI.
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_INIT);
+}
+
+/// Sends a startup IPI, which starts the target CPU in real mode at
+/// `page * 4096`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | u32::from(page));
+}
+
+/// Interrupts the target CPU on [`WAKEUP_VECTOR`], bringing it out of `hlt`.
+pub fn send_wakeup(apic_id: u32) {
+    send_ipi(apic_id, ICR_FIXED | u32::from(WAKEUP_VECTOR));
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    let local_apic = *LOCAL_APIC.get().expect("apic not initialized");
+
+    interrupts::without_interrupts(|| {
+        unsafe { mmio_write(local_apic + LAPIC_ICR_HIGH, apic_id << 24) };
+        unsafe { mmio_write(local_apic + LAPIC_ICR_LOW, command) };
+
+        while unsafe { mmio_read(local_apic + LAPIC_ICR_LOW) } & ICR_DELIVERY_PENDING != 0 {
+            spin_loop();
+        }
+    });
+}
+
+#[must_use]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn end_of_interrupt() {
+    if let Some(&local_apic) = LOCAL_APIC.get() {
+        unsafe { mmio_write(local_apic + LAPIC_EOI, 0) };
+    }
+}
+
+/// Masks or unmasks the IO-APIC entry a legacy IRQ is routed to.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let (Some(routes), Some(io_apic)) = (ROUTES.get(), IO_APIC.get()) else {
+        return;
+    };
+
+    let Some(gsi) = routes[irq as usize] else {
+        return;
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut io_apic = io_apic.lock();
+
+        let register = io_apic.redirection(gsi);
+        let low = io_apic.read(register);
+
+        let low = if masked {
+            low | REDIRECTION_MASKED
+        } else {
+            low & !REDIRECTION_MASKED
+        };
+
+        io_apic.write(register, low);
+    });
+}
+
+fn route_irq(irq: u8, gsi: u32, irq_override: Option<&InterruptOverride>, destination: u32) {
+    let mut low = u32::from(interrupt_index(irq)) | REDIRECTION_MASKED;
+
+    if irq_override.is_some_and(|o| o.active_low) {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+
+    if irq_override.is_some_and(|o| o.level_triggered) {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+
+    let mut io_apic = IO_APIC.get().unwrap().lock();
+
+    let register = io_apic.redirection(gsi);
+
+    io_apic.write(register + 1, destination << 24);
+    io_apic.write(register, low);
+}
+
+unsafe fn mmio_read(addr: VirtAddr) -> u32 {
+    unsafe { addr.as_ptr::<u32>().read_volatile() }
+}
+
+unsafe fn mmio_write(addr: VirtAddr, value: u32) {
+    unsafe { addr.as_mut_ptr::<u32>().write_volatile(value) };
+}
@@ -1,21 +1,105 @@
 use crate::{
     log,
-    sys::{self, cmos::Cmos},
+    sys::{self, clocksource, cmos::Cmos, thread, timer},
 };
 use alloc::string::{String, ToString};
 use chrono::DateTime;
+use core::ops::{Add, AddAssign, Sub};
+pub use core::time::Duration;
 use num_traits::float::FloatCore;
 use x86_64::instructions::interrupts;
 
 const DAYS_BEFORE_MONTH: [u64; 13] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334, 365];
 
+/// A point in monotonic time, in nanoseconds since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const MAX: Self = Self(u64::MAX);
+
+    #[must_use]
+    pub fn now() -> Self {
+        Self(clocksource::nanoseconds())
+    }
+
+    #[must_use]
+    pub const fn from_nanos(nanoseconds: u64) -> Self {
+        Self(nanoseconds)
+    }
+
+    #[must_use]
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is later.
+    #[must_use]
+    pub const fn duration_since(self, earlier: Self) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    #[must_use]
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+
+    #[must_use]
+    pub fn checked_add(self, duration: Duration) -> Option<Self> {
+        let nanoseconds = u64::try_from(duration.as_nanos()).ok()?;
+
+        self.0.checked_add(nanoseconds).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Self) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Blocks the calling thread for `seconds`, or halts the CPU before threads
+/// are set up. Tasks should use
+/// [`task::timer::sleep`](super::task::timer::sleep) instead, which lets
+/// others run in the meantime.
 pub fn sleep(seconds: f64) {
-    let start = uptime();
+    let duration = Duration::try_from_secs_f64(seconds).unwrap_or_default();
+
+    sleep_until(Instant::now().checked_add(duration).unwrap_or(Instant::MAX));
+}
+
+pub fn sleep_until(deadline: Instant) {
+    if thread::is_initialized() {
+        thread::sleep_until(deadline);
 
-    #[allow(clippy::while_float)]
-    while uptime() - start < seconds {
+        return;
+    }
+
+    // nothing to do but make sure the halted CPU is woken up
+    let timer = timer::call_at(deadline, || {});
+
+    while Instant::now() < deadline {
         halt();
     }
+
+    timer::cancel(timer);
 }
 
 pub fn halt() {
@@ -28,10 +112,11 @@ pub fn halt() {
     }
 }
 
+/// Seconds since boot; [`Instant`] keeps full precision.
 #[must_use]
 #[allow(clippy::cast_precision_loss)]
 pub fn uptime() -> f64 {
-    sys::time::time_between_ticks() * sys::time::ticks() as f64
+    Instant::now().as_nanos() as f64 / 1e9
 }
 
 #[must_use]
@@ -46,8 +131,8 @@ pub fn realtime() -> f64 {
         + 60 * u64::from(rtc.minute)
         + u64::from(rtc.second);
 
-    let fract = sys::time::time_between_ticks()
-        * (sys::time::ticks() - sys::time::last_rtc_update()) as f64;
+    let last_update = sys::time::last_rtc_update();
+    let fract = clocksource::nanoseconds().saturating_sub(last_update) as f64 / 1e9;
 
     (timestamp as f64) + fract
 }
@@ -0,0 +1,197 @@
+use super::{hpet, time};
+use crate::log;
+use core::{
+    fmt,
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+use raw_cpuid::CpuId;
+use x86_64::instructions::interrupts;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+const HPET_CALIBRATION_NANOSECONDS: u64 = 10_000_000;
+const PIT_CALIBRATION_TICKS: usize = 4;
+
+/// Where monotonic time comes from, from worst to best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum ClockSource {
+    Pit,
+    Hpet,
+    Tsc,
+}
+
+impl ClockSource {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Hpet,
+            2 => Self::Tsc,
+            _ => Self::Pit,
+        }
+    }
+
+    /// Nanoseconds since some point fixed by the source itself.
+    fn read(self) -> u64 {
+        match self {
+            Self::Pit => time::pit_nanoseconds(),
+            Self::Hpet => hpet::nanoseconds().unwrap_or(0),
+            Self::Tsc => cycles_to_nanoseconds(rdtsc()),
+        }
+    }
+}
+
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pit => write!(f, "pit"),
+            Self::Hpet => write!(f, "hpet"),
+            Self::Tsc => write!(f, "tsc"),
+        }
+    }
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+
+/// Reading of the current source when it was selected, and the monotonic
+/// time at that moment, so time carries on across the switch.
+static BASE: AtomicU64 = AtomicU64::new(0);
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds per TSC cycle, as a 32.32 fixed point number.
+static TSC_SCALE: AtomicU64 = AtomicU64::new(0);
+
+/// Calibrates the TSC and picks the best clock source.
+///
+/// The TSC is timed against the HPET, or the PIT without one, so interrupts
+/// must be enabled. It becomes the source if it is invariant, otherwise the
+/// HPET does, then the PIT.
+pub fn init() {
+    let has_hpet = hpet::init();
+
+    let frequency = if has_hpet {
+        calibrate_with_hpet()
+    } else {
+        calibrate_with_pit()
+    };
+
+    let scale = (u128::from(NANOSECONDS_PER_SECOND) << 32) / u128::from(frequency.max(1));
+
+    TSC_FREQUENCY.store(frequency, Ordering::SeqCst);
+    TSC_SCALE.store(u64::try_from(scale).unwrap_or(u64::MAX), Ordering::SeqCst);
+
+    let invariant = CpuId::new()
+        .get_advanced_power_mgmt_info()
+        .is_some_and(|info| info.has_invariant_tsc());
+
+    let source = if invariant {
+        ClockSource::Tsc
+    } else if has_hpet {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Pit
+    };
+
+    select(source);
+
+    log!(
+        "clock source {source}, tsc {} MHz{}",
+        frequency / 1_000_000,
+        if invariant { " invariant" } else { "" }
+    );
+}
+
+fn select(source: ClockSource) {
+    interrupts::without_interrupts(|| {
+        let now = nanoseconds();
+
+        BASE.store(source.read(), Ordering::SeqCst);
+        OFFSET.store(now, Ordering::SeqCst);
+        SOURCE.store(source as u8, Ordering::SeqCst);
+    });
+}
+
+#[must_use]
+pub fn current() -> ClockSource {
+    ClockSource::from_u8(SOURCE.load(Ordering::SeqCst))
+}
+
+/// Monotonic nanoseconds since the PIT started ticking.
+#[must_use]
+pub fn nanoseconds() -> u64 {
+    let source = current();
+
+    let elapsed = source.read().saturating_sub(BASE.load(Ordering::SeqCst));
+
+    OFFSET.load(Ordering::SeqCst) + elapsed
+}
+
+/// TSC cycles per second, or 0 before calibration.
+#[must_use]
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn cycles_to_nanoseconds(cycles: u64) -> u64 {
+    ((u128::from(cycles) * u128::from(TSC_SCALE.load(Ordering::Relaxed))) >> 32) as u64
+}
+
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn nanoseconds_to_cycles(nanoseconds: u64) -> u64 {
+    (u128::from(nanoseconds) * u128::from(tsc_frequency()) / u128::from(NANOSECONDS_PER_SECOND))
+        as u64
+}
+
+#[must_use]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_mm_lfence() };
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn calibrate_with_hpet() -> u64 {
+    let start = hpet::nanoseconds().unwrap();
+    let start_cycles = rdtsc();
+
+    let mut now = start;
+
+    while now - start < HPET_CALIBRATION_NANOSECONDS {
+        spin_loop();
+
+        now = hpet::nanoseconds().unwrap();
+    }
+
+    let cycles = rdtsc() - start_cycles;
+
+    frequency(cycles, now - start)
+}
+
+fn calibrate_with_pit() -> u64 {
+    let wait_until = |ticks| {
+        while time::ticks() < ticks {
+            spin_loop();
+        }
+    };
+
+    // start on a tick edge
+    wait_until(time::ticks() + 1);
+
+    let start = time::pit_nanoseconds();
+    let start_cycles = rdtsc();
+
+    wait_until(time::ticks() + PIT_CALIBRATION_TICKS);
+
+    let cycles = rdtsc() - start_cycles;
+
+    frequency(cycles, time::pit_nanoseconds() - start)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn frequency(cycles: u64, nanoseconds: u64) -> u64 {
+    (u128::from(cycles) * u128::from(NANOSECONDS_PER_SECOND) / u128::from(nanoseconds.max(1)))
+        as u64
+}
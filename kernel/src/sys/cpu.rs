@@ -1,5 +1,19 @@
 use crate::log;
-use raw_cpuid::CpuId;
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use raw_cpuid::{CpuId, ExtendedFeatures};
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    model_specific::{Efer, EferFlags},
+};
+
+static NX: AtomicBool = AtomicBool::new(false);
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+static FEATURES: OnceCell<Features> = OnceCell::uninit();
 
 pub fn init() {
     let cpuid = CpuId::new();
@@ -17,4 +31,88 @@ pub fn init() {
 
         log!("CPU {} MHz", processor_base_frequency);
     }
+
+    let extended = cpuid.get_extended_feature_info();
+
+    let features = Features {
+        nx: cpuid
+            .get_extended_processor_and_feature_identifiers()
+            .is_some_and(|info| info.has_execute_disable()),
+        smep: extended.as_ref().is_some_and(ExtendedFeatures::has_smep),
+        smap: extended.as_ref().is_some_and(ExtendedFeatures::has_smap),
+    };
+
+    harden(features);
+
+    FEATURES.init_once(|| features);
+
+    log!(
+        "CPU nx {}, smep {}, smap {}, wp true",
+        features.nx,
+        features.smep,
+        features.smap
+    );
+}
+
+/// Applies the protections enabled on the bootstrap processor to an
+/// application processor.
+pub fn init_ap() {
+    harden(*FEATURES.get().expect("cpu features not detected"));
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Features {
+    nx: bool,
+    smep: bool,
+    smap: bool,
+}
+
+/// Enables every protection the CPU supports, plus write protection so the
+/// kernel cannot write to read-only pages either.
+fn harden(features: Features) {
+    if features.nx {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+
+        NX.store(true, Ordering::Relaxed);
+    }
+
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+
+    if features.smep {
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION));
+        }
+    }
+
+    if features.smap {
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION));
+        }
+
+        SMAP.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether page table entries may use the no-execute bit.
+#[must_use]
+pub fn has_nx() -> bool {
+    NX.load(Ordering::Relaxed)
+}
+
+/// Runs `f` with user pages accessible to the kernel. With SMAP enabled this
+/// is the only place the kernel may touch user memory.
+pub fn with_user_access<T>(f: impl FnOnce() -> T) -> T {
+    let smap = SMAP.load(Ordering::Relaxed);
+
+    if smap {
+        unsafe { asm!("stac", options(nomem, nostack)) };
+    }
+
+    let result = f();
+
+    if smap {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+
+    result
 }
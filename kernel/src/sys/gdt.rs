@@ -1,7 +1,11 @@
+use super::memory::stack::KernelStack;
 use crate::log;
+use alloc::{boxed::Box, format};
 use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::{
     instructions::{
+        interrupts,
         segmentation::{Segment, CS},
         tables::load_tss,
     },
@@ -14,33 +18,43 @@ use x86_64::{
 };
 
 lazy_static! {
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
+    // the TSS lives in a static, so the pointer stays valid
+    pub static ref GDT: (GlobalDescriptorTable, Selectors) =
+        unsafe { build(&raw const *TSS.lock()) };
+}
 
-        let tss = gdt.append(Descriptor::tss_segment(&TSS));
+/// Lays out a GDT around `tss`. Every CPU gets the same layout, so the
+/// selectors are interchangeable between them.
+///
+/// # Safety
+///
+/// `tss` must stay valid for as long as the GDT is in use.
+unsafe fn build(tss: *const TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
 
-        let code = gdt.append(Descriptor::kernel_code_segment());
+    let tss = gdt.append(unsafe { Descriptor::tss_segment_unchecked(tss) });
 
-        let data = gdt.append(Descriptor::kernel_data_segment());
+    let code = gdt.append(Descriptor::kernel_code_segment());
 
-        let stack = gdt.append(Descriptor::kernel_data_segment());
+    let data = gdt.append(Descriptor::kernel_data_segment());
 
-        let user_code = gdt.append(Descriptor::user_code_segment());
+    let stack = gdt.append(Descriptor::kernel_data_segment());
 
-        let user_data = gdt.append(Descriptor::user_data_segment());
+    let user_code = gdt.append(Descriptor::user_code_segment());
 
-        (
-            gdt,
-            Selectors {
-                code,
-                data,
-                stack,
-                tss,
-                user_code,
-                user_data,
-            },
-        )
-    };
+    let user_data = gdt.append(Descriptor::user_data_segment());
+
+    (
+        gdt,
+        Selectors {
+            code,
+            data,
+            stack,
+            tss,
+            user_code,
+            user_data,
+        },
+    )
 }
 
 #[derive(Debug)]
@@ -54,48 +68,99 @@ pub struct Selectors {
 }
 
 pub fn init() {
-    GDT.0.load();
+    load(&GDT);
+
+    log!("gdt loaded");
+}
 
-    unsafe { CS::set_reg(GDT.1.code) };
-    unsafe { DS::set_reg(GDT.1.data) };
-    unsafe { SS::set_reg(GDT.1.stack) };
+/// Gives an application processor a TSS with its own guarded stacks, and a
+/// GDT to hold it.
+pub fn init_ap(cpu: usize) {
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
 
-    unsafe { load_tss(GDT.1.tss) };
+    allocate_stacks(tss, Some(cpu));
 
-    log!("gdt loaded");
+    let gdt = Box::leak(Box::new(unsafe { build(tss) }));
+
+    load(gdt);
 }
 
-const STACK_SIZE: usize = 4096 * 5;
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    gdt.0.load();
+
+    unsafe { CS::set_reg(gdt.1.code) };
+    unsafe { DS::set_reg(gdt.1.data) };
+    unsafe { SS::set_reg(gdt.1.stack) };
+
+    unsafe { load_tss(gdt.1.tss) };
+}
+
+const STACK_SIZE: u64 = 4096 * 5;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 2;
 
+/// Only used until [`init_stacks`] replaces it with guarded stacks, as
+/// those need the memory manager.
+const BOOT_STACK_SIZE: usize = 4096 * 2;
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Mutex<TaskStateSegment> = {
         let mut tss = TaskStateSegment::new();
 
         tss.privilege_stack_table[0] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
+            static mut STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+            VirtAddr::from_ptr(&raw const STACK) + BOOT_STACK_SIZE as u64
         };
 
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
-            VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
+            VirtAddr::from_ptr(&raw const STACK) + BOOT_STACK_SIZE as u64
         };
 
         tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
-            VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
+            VirtAddr::from_ptr(&raw const STACK) + BOOT_STACK_SIZE as u64
         };
 
         tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE as u64
+            static mut STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+            VirtAddr::from_ptr(&raw const STACK) + BOOT_STACK_SIZE as u64
         };
 
-        tss
+        Mutex::new(tss)
     };
 }
+
+/// Stacks every CPU needs, with the IST slot each one goes in.
+const STACKS: [(&str, Option<u16>); 4] = [
+    ("privilege", None),
+    ("double fault", Some(DOUBLE_FAULT_IST_INDEX)),
+    ("page fault", Some(PAGE_FAULT_IST_INDEX)),
+    (
+        "general protection fault",
+        Some(GENERAL_PROTECTION_FAULT_IST_INDEX),
+    ),
+];
+
+/// Moves the privilege and interrupt stacks onto guarded kernel stacks.
+pub fn init_stacks() {
+    interrupts::without_interrupts(|| allocate_stacks(&mut TSS.lock(), None));
+
+    log!("kernel stacks guarded");
+}
+
+fn allocate_stacks(tss: &mut TaskStateSegment, cpu: Option<usize>) {
+    for (name, ist_index) in STACKS {
+        let name = cpu.map_or(name, |cpu| format!("cpu {cpu} {name}").leak());
+
+        let stack = KernelStack::new(name, STACK_SIZE).expect("failed to allocate kernel stack");
+
+        match ist_index {
+            Some(index) => tss.interrupt_stack_table[index as usize] = stack.top(),
+            None => tss.privilege_stack_table[0] = stack.top(),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use super::{acpi, memory::vmm};
+use crate::log;
+use conquer_once::spin::OnceCell;
+use x86_64::VirtAddr;
+
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xF0;
+
+const COUNTER_64_BIT: u64 = 1 << 13;
+const ENABLE: u64 = 1 << 0;
+
+/// The spec caps the counter period at 100 ns.
+const MAX_PERIOD_FEMTOSECONDS: u64 = 100_000_000;
+
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+struct Hpet {
+    base: VirtAddr,
+    period: u64,
+}
+
+impl Hpet {
+    fn read(&self, register: u64) -> u64 {
+        unsafe { (self.base + register).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&self, register: u64, value: u64) {
+        let ptr = (self.base + register).as_mut_ptr::<u64>();
+
+        unsafe { ptr.write_volatile(value) };
+    }
+}
+
+/// Starts the main counter of the HPET described by ACPI. Returns `false` if
+/// there is none, or only one with a 32-bit counter, which would wrap too
+/// often to keep time with.
+pub fn init() -> bool {
+    let Some(table) = acpi::hpet::parse() else {
+        return false;
+    };
+
+    let base = vmm::map_mmio(table.base, 0x400).expect("failed to map the hpet");
+
+    let hpet = Hpet { base, period: 0 };
+
+    let capabilities = hpet.read(CAPABILITIES);
+    let period = capabilities >> 32;
+
+    if capabilities & COUNTER_64_BIT == 0 || period == 0 || period > MAX_PERIOD_FEMTOSECONDS {
+        log!("hpet unusable, capabilities {capabilities:#x}");
+
+        return false;
+    }
+
+    hpet.write(CONFIGURATION, hpet.read(CONFIGURATION) | ENABLE);
+
+    let timers = ((capabilities >> 8) & 0x1F) + 1;
+
+    log!(
+        "hpet initialized, {} kHz, {timers} timers",
+        1_000_000_000_000 / period
+    );
+
+    HPET.init_once(|| Hpet { period, ..hpet });
+
+    true
+}
+
+#[must_use]
+pub fn is_enabled() -> bool {
+    HPET.get().is_some()
+}
+
+/// Nanoseconds since the main counter was started.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn nanoseconds() -> Option<u64> {
+    let hpet = HPET.get()?;
+
+    let femtoseconds = u128::from(hpet.read(MAIN_COUNTER)) * u128::from(hpet.period);
+
+    Some((femtoseconds / FEMTOSECONDS_PER_NANOSECOND) as u64)
+}
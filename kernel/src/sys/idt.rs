@@ -1,7 +1,7 @@
 use super::{
-    gdt,
+    apic, gdt, memory,
     pic::{PICS, PIC_1_OFFSET},
-    syscall,
+    process, syscall, thread, timer,
 };
 use crate::{log, println};
 use core::arch::naked_asm;
@@ -58,6 +58,10 @@ lazy_static! {
                 .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
         }
 
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
+        idt[apic::TIMER_VECTOR].set_handler_fn(timer_interrupt_handler);
+        idt[apic::WAKEUP_VECTOR].set_handler_fn(wakeup_interrupt_handler);
+
         idt[interrupt_index(0)].set_handler_fn(irq0_handler);
         idt[interrupt_index(1)].set_handler_fn(irq1_handler);
         idt[interrupt_index(2)].set_handler_fn(irq2_handler);
@@ -100,14 +104,24 @@ macro_rules! irq_handler {
         pub extern "x86-interrupt" fn $handler(_stack_frame: InterruptStackFrame) {
             let handlers = IRQ_HANDLERS.lock();
             handlers[$irq]();
-            unsafe {
-                PICS.lock().notify_end_of_interrupt(interrupt_index($irq));
-            }
+            end_of_interrupt($irq);
         }
     };
 }
 
-irq_handler!(irq0_handler, 0);
+// without an APIC, the PIT tick is what ends time slices
+pub extern "x86-interrupt" fn irq0_handler(stack_frame: InterruptStackFrame) {
+    // copied out, so the lock is not held if this thread is switched away
+    let handler = IRQ_HANDLERS.lock()[Irq::Timer as usize];
+
+    handler();
+    end_of_interrupt(Irq::Timer as u8);
+
+    if timer::is_pit_tick() {
+        thread::preempt(&stack_frame);
+    }
+}
+
 irq_handler!(irq1_handler, 1);
 irq_handler!(irq2_handler, 2);
 irq_handler!(irq3_handler, 3);
@@ -124,12 +138,41 @@ irq_handler!(irq13_handler, 13);
 irq_handler!(irq14_handler, 14);
 irq_handler!(irq15_handler, 15);
 
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(interrupt_index(irq)) };
+    }
+}
+
+// spurious interrupts are not acknowledged
+const extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    timer::interrupt();
+    apic::end_of_interrupt();
+
+    thread::preempt(&stack_frame);
+}
+
+// ends a `hlt`, and lets a thread readied from another CPU run
+extern "x86-interrupt" fn wakeup_interrupt_handler(stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+
+    thread::preempt(&stack_frame);
+}
+
 pub fn init() {
     IDT.load();
 
     log!("idt loaded");
 }
 
+pub fn init_ap() {
+    IDT.load();
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT");
     println!("Stack Frame: {:#?}", stack_frame);
@@ -139,6 +182,10 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    if let Some(stack) = Cr2::read().ok().and_then(memory::stack::guard_hit) {
+        panic!("kernel stack overflow in the {stack} stack");
+    }
+
     println!("EXCEPTION: DOUBLE FAULT");
     println!("Stack Frame: {:#?}", stack_frame);
     println!("Error Code: {}", error_code);
@@ -149,6 +196,22 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    if let Ok(addr) = Cr2::read() {
+        match memory::fault::handle(addr, error_code) {
+            Ok(()) => return,
+            Err(err) if error_code.contains(PageFaultErrorCode::USER_MODE) => {
+                log!("page fault at {addr:?} in user mode: {err}");
+
+                process::kill();
+            }
+            Err(err) => println!("page fault at {addr:?}: {err}"),
+        }
+
+        if let Some(stack) = memory::stack::guard_hit(addr) {
+            panic!("kernel stack overflow in the {stack} stack");
+        }
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Stack Frame: {:#?}", stack_frame);
@@ -201,10 +264,22 @@ macro_rules! wrap {
                     "push r9",
                     "push r10",
                     "push r11",
+                    "push rbx",
+                    "push rbp",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
                     "mov rsi, rsp", // Arg #2: register list
                     "mov rdi, rsp", // Arg #1: interupt frame
-                    "add rdi, 9 * 8",
+                    "add rdi, 15 * 8",
                     "call {}",
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop rbp",
+                    "pop rbx",
                     "pop r11",
                     "pop r10",
                     "pop r9",
@@ -224,7 +299,9 @@ macro_rules! wrap {
 
 wrap!(syscall_handler => wrapped_syscall_handler);
 
-extern "sysv64" fn syscall_handler(_stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+extern "sysv64" fn syscall_handler(stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+    process::save_context(stack_frame, regs);
+
     let n = regs.rax;
     let arg1 = regs.rdi;
     let arg2 = regs.rsi;
@@ -234,8 +311,6 @@ extern "sysv64" fn syscall_handler(_stack_frame: &mut InterruptStackFrame, regs:
     let result = syscall::dispatcher(n, arg1, arg2, arg3, arg4);
 
     regs.rax = result;
-
-    unsafe { PICS.lock().notify_end_of_interrupt(0x80) };
 }
 
 pub fn set_irq_handler(irq: u8, handler: fn()) {
@@ -248,6 +323,12 @@ pub fn set_irq_handler(irq: u8, handler: fn()) {
 }
 
 pub fn set_irq_mask(irq: u8) {
+    if apic::is_enabled() {
+        apic::set_irq_masked(irq, true);
+
+        return;
+    }
+
     let mut port: Port<u8> = Port::new(if irq < 8 { PIC1 } else { PIC2 });
 
     let value = unsafe { port.read() } | (1 << (if irq < 8 { irq } else { irq - 8 }));
@@ -255,6 +336,12 @@ pub fn set_irq_mask(irq: u8) {
 }
 
 pub fn clear_irq_mask(irq: u8) {
+    if apic::is_enabled() {
+        apic::set_irq_masked(irq, false);
+
+        return;
+    }
+
     let mut port: Port<u8> = Port::new(if irq < 8 { PIC1 } else { PIC2 });
 
     let value = unsafe { port.read() } & !(1 << if irq < 8 { irq } else { irq - 8 });
@@ -264,6 +351,12 @@ pub fn clear_irq_mask(irq: u8) {
 #[repr(align(8), C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Registers {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbp: usize,
+    pub rbx: usize,
     pub r11: usize,
     pub r10: usize,
     pub r9: usize,
@@ -1,89 +0,0 @@
-use super::allocator;
-use crate::log;
-use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
-use x86_64::{
-    instructions::interrupts,
-    registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
-};
-
-pub static mut PHYS_MEM_OFFSET: u64 = 0;
-
-pub fn init(physical_memory_offset: u64, memory_regions: &'static MemoryRegions) {
-    interrupts::without_interrupts(|| {
-        let phys_mem_offset = VirtAddr::new(physical_memory_offset);
-
-        unsafe {
-            PHYS_MEM_OFFSET = physical_memory_offset;
-        }
-
-        let mut mapper = unsafe { mapper(phys_mem_offset) };
-
-        let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(memory_regions) };
-
-        allocator::init_heap(&mut mapper, &mut frame_allocator)
-            .expect("heap initialization failed");
-    });
-
-    log!("memory initialized");
-}
-
-unsafe fn mapper(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level_4_table = unsafe { active_level_4_table(physical_memory_offset) };
-
-    unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) }
-}
-
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-
-    unsafe { &mut *page_table_ptr }
-}
-
-struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    const unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        Self {
-            memory_map,
-            next: 0,
-        }
-    }
-}
-
-impl BootInfoFrameAllocator {
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-
-        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
-
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-
-        self.next += 1;
-
-        frame
-    }
-}
-
-#[must_use]
-pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
-    VirtAddr::new(addr.as_u64() + unsafe { PHYS_MEM_OFFSET })
-}
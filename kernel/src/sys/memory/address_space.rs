@@ -0,0 +1,569 @@
+use super::{
+    fault::{self, PageFaultError},
+    frame::{self, GlobalFrameAllocator, FRAME_SIZE},
+    phys_to_virt, supported_flags,
+    vma::{Areas, OverlapError, Vma, VmaKind},
+    vmm,
+};
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::control::{Cr3, Cr3Flags},
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+            page_table::PageTableEntry,
+            Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+            Translate,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// User programs live in level 4 entries 64..128; everything else is the
+/// kernel's and is shared by every address space.
+const USER_L4_ENTRIES: Range<usize> = 64..128;
+
+pub const USER_START: u64 = (USER_L4_ENTRIES.start as u64) << 39;
+pub const USER_END: u64 = (USER_L4_ENTRIES.end as u64) << 39;
+
+/// Marks leaf entries whose frame belongs to the address space and is freed
+/// with it.
+pub const OWNED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Marks owned leaf entries that were writable before a fork made them
+/// shared; the first write gets its own copy of the frame.
+pub const COW: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Lazily backed areas of every address space, keyed by level 4 table so the
+/// page fault handler can find them from CR3.
+static USER_AREAS: Mutex<BTreeMap<PhysFrame, Areas>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an empty user address space sharing the kernel's mappings.
+    #[must_use]
+    pub fn new() -> Option<Self> {
+        let level_4_frame = frame::allocate_zeroed()?;
+
+        let table = unsafe { table_mut(level_4_frame) };
+
+        vmm::with_mapper(|mapper| {
+            let kernel_table = mapper.level_4_table();
+
+            for (i, entry) in kernel_table.iter().enumerate() {
+                if USER_L4_ENTRIES.contains(&i) {
+                    assert!(entry.is_unused(), "kernel mapping in user address space");
+                } else {
+                    table[i] = entry.clone();
+                }
+            }
+        });
+
+        interrupts::without_interrupts(|| {
+            USER_AREAS.lock().insert(level_4_frame, Areas::new());
+        });
+
+        Some(Self { level_4_frame })
+    }
+
+    #[must_use]
+    pub const fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// # Safety
+    ///
+    /// The currently executing code and stack must be kernel mappings.
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            unsafe { Cr3::write(self.level_4_frame, Cr3Flags::empty()) };
+        }
+    }
+
+    /// Backs a user range with fresh zeroed frames owned by this address
+    /// space.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            is_user_range(start, size),
+            "{start:?} is not a user address"
+        );
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | OWNED;
+
+        for page in pages(start, size) {
+            let frame = frame::allocate_zeroed().ok_or(MapToError::FrameAllocationFailed)?;
+
+            if let Err(err) = unsafe { self.map_page(page, frame, flags) } {
+                frame::deallocate(frame);
+
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maps a user range onto existing physical memory, which is not freed
+    /// with the address space.
+    ///
+    /// # Safety
+    ///
+    /// The physical range must be safe to expose to user code.
+    pub unsafe fn map_to(
+        &mut self,
+        start: VirtAddr,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            is_user_range(start, size),
+            "{start:?} is not a user address"
+        );
+
+        let flags = (flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) - OWNED;
+        let first = PhysFrame::containing_address(phys);
+
+        for (i, page) in pages(start, size).enumerate() {
+            unsafe { self.map_page(page, first + i as u64, flags)? };
+        }
+
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), UnmapError> {
+        assert!(
+            is_user_range(start, size),
+            "{start:?} is not a user address"
+        );
+
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+
+        for page in pages(start, size) {
+            let owned = leaf_flags(&mapper, page).is_some_and(|flags| flags.contains(OWNED));
+
+            let (frame, flush) = mapper.unmap(page)?;
+
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+
+            if owned {
+                frame::deallocate(frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reserves a user range that is backed with zeroed frames as it is
+    /// touched.
+    pub fn reserve(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        kind: VmaKind,
+    ) -> Result<(), OverlapError> {
+        assert!(
+            is_user_range(start, size),
+            "{start:?} is not a user address"
+        );
+
+        let vma = Vma::new(start, size, flags | PageTableFlags::USER_ACCESSIBLE, kind);
+
+        self.with_areas(|areas| areas.insert(vma))
+    }
+
+    /// Drops a reserved range along with any frames backing it.
+    pub fn release(&mut self, start: VirtAddr) -> Option<Vma> {
+        let vma = self.with_areas(|areas| areas.remove(start))?;
+
+        let active = self.is_active();
+
+        fault::depopulate(&mut self.mapper(), &vma, active);
+
+        Some(vma)
+    }
+
+    /// Creates a child sharing every owned frame with this address space.
+    /// Writable pages become read-only on both sides and are copied on the
+    /// first write.
+    #[must_use]
+    pub fn fork(&mut self) -> Option<Self> {
+        let child = Self::new()?;
+
+        let areas = self.with_areas(|areas| areas.clone());
+        child.with_areas(|child_areas| *child_areas = areas);
+
+        let parent_table = unsafe { table_mut(self.level_4_frame) };
+        let child_table = unsafe { table_mut(child.level_4_frame) };
+
+        let result = USER_L4_ENTRIES
+            .clone()
+            .try_for_each(|i| fork_entry(&mut parent_table[i], &mut child_table[i], 4));
+
+        if self.is_active() {
+            tlb::flush_all();
+        }
+
+        result.map(|()| child)
+    }
+
+    fn with_areas<T>(&self, f: impl FnOnce(&mut Areas) -> T) -> T {
+        interrupts::without_interrupts(|| {
+            let mut areas = USER_AREAS.lock();
+
+            f(areas.get_mut(&self.level_4_frame).unwrap())
+        })
+    }
+
+    #[must_use]
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let level_4_table = unsafe { table_mut(self.level_4_frame) };
+
+        let mapper = unsafe { OffsetPageTable::new(level_4_table, phys_to_virt(PhysAddr::zero())) };
+
+        mapper.translate_addr(addr)
+    }
+
+    unsafe fn map_page(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let active = self.is_active();
+
+        let flush = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                supported_flags(flags),
+                parent_flags,
+                &mut GlobalFrameAllocator,
+            )?
+        };
+
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+
+        Ok(())
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let level_4_table = unsafe { table_mut(self.level_4_frame) };
+
+        unsafe { OffsetPageTable::new(level_4_table, phys_to_virt(PhysAddr::zero())) }
+    }
+}
+
+/// Gives every kernel level 4 entry a level 3 table up front. Address spaces
+/// copy the level 4 entries when they are created, so kernel mappings made
+/// later must land in tables they already share.
+pub(super) fn init() {
+    vmm::with_mapper(|mapper| {
+        let kernel_table = mapper.level_4_table_mut();
+
+        for (i, entry) in kernel_table.iter_mut().enumerate() {
+            if USER_L4_ENTRIES.contains(&i) || !entry.is_unused() {
+                continue;
+            }
+
+            let frame = frame::allocate_zeroed().expect("no memory for kernel page tables");
+
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    });
+}
+
+/// The frame of the kernel's own level 4 page table.
+#[must_use]
+pub fn kernel_level_4_frame() -> PhysFrame {
+    vmm::with_mapper(|mapper| {
+        let table = core::ptr::from_ref(mapper.level_4_table());
+
+        let phys = VirtAddr::from_ptr(table) - phys_to_virt(PhysAddr::zero());
+
+        PhysFrame::containing_address(PhysAddr::new(phys))
+    })
+}
+
+/// Switches back to the kernel's own page table, e.g. before the current
+/// address space is dropped.
+///
+/// # Safety
+///
+/// Nothing running afterwards may rely on user mappings.
+pub unsafe fn activate_kernel() {
+    let frame = kernel_level_4_frame();
+
+    if Cr3::read().0 != frame {
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let level_4_table = unsafe { table_mut(self.level_4_frame) };
+
+        let user_entries = level_4_table
+            .iter_mut()
+            .take(USER_L4_ENTRIES.end)
+            .skip(USER_L4_ENTRIES.start);
+
+        for entry in user_entries {
+            if !entry.is_unused() {
+                free_table(entry.frame().unwrap(), 3);
+                entry.set_unused();
+            }
+        }
+
+        interrupts::without_interrupts(|| USER_AREAS.lock().remove(&self.level_4_frame));
+
+        frame::deallocate(self.level_4_frame);
+    }
+}
+
+/// Backs the page at `addr` if it lies in a reserved area of the active
+/// address space.
+pub(super) fn populate(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), PageFaultError> {
+    let area = *USER_AREAS
+        .lock()
+        .get(&Cr3::read().0)
+        .and_then(|areas| areas.find(addr))
+        .ok_or(PageFaultError::Unreserved)?;
+
+    fault::populate(&mut active_mapper(), &area, addr, error_code, OWNED)
+}
+
+/// Whether user code could make this access in the active address space,
+/// either to mapped pages or to reserved ones a page fault would back.
+#[must_use]
+pub fn is_accessible(start: VirtAddr, size: u64, write: bool) -> bool {
+    if size == 0 {
+        return true;
+    }
+
+    if !is_user_range(start, size) {
+        return false;
+    }
+
+    let mut error_code = PageFaultErrorCode::USER_MODE;
+
+    if write {
+        error_code |= PageFaultErrorCode::CAUSED_BY_WRITE;
+    }
+
+    let mapper = active_mapper();
+
+    interrupts::without_interrupts(|| {
+        let areas = USER_AREAS.lock();
+        let areas = areas.get(&Cr3::read().0);
+
+        pages(start, size).all(|page| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => {
+                flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                    && (!write || flags.intersects(PageTableFlags::WRITABLE | COW))
+            }
+            _ => areas
+                .and_then(|areas| areas.find(page.start_address()))
+                .is_some_and(|area| area.allows(error_code)),
+        })
+    })
+}
+
+/// Gives the page at `addr` in the active address space a private writable
+/// frame, copying it if the frame is still shared.
+pub(super) fn copy_on_write(addr: VirtAddr) -> Result<(), PageFaultError> {
+    let mut mapper = active_mapper();
+    let page = Page::containing_address(addr);
+
+    let TranslateResult::Mapped {
+        frame: MappedFrame::Size4KiB(frame),
+        flags,
+        ..
+    } = mapper.translate(addr)
+    else {
+        return Err(PageFaultError::Protection);
+    };
+
+    if !flags.contains(COW) {
+        return Err(PageFaultError::Protection);
+    }
+
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+    if frame::references(frame) == 1 {
+        let flush =
+            unsafe { mapper.update_flags(page, flags) }.map_err(|_| PageFaultError::Protection)?;
+
+        flush.flush();
+
+        return Ok(());
+    }
+
+    let copy = frame::allocate().ok_or(PageFaultError::OutOfMemory)?;
+
+    let src = phys_to_virt(frame.start_address()).as_ptr::<u8>();
+    let dst = phys_to_virt(copy.start_address()).as_mut_ptr::<u8>();
+
+    #[allow(clippy::cast_possible_truncation)]
+    unsafe {
+        core::ptr::copy_nonoverlapping(src, dst, FRAME_SIZE as usize);
+    }
+
+    let (_, flush) = mapper.unmap(page).map_err(|_| PageFaultError::Protection)?;
+    flush.ignore();
+
+    let flush = unsafe { mapper.map_to(page, copy, flags, &mut GlobalFrameAllocator) }
+        .map_err(|_| PageFaultError::OutOfMemory)?;
+    flush.flush();
+
+    frame::deallocate(frame);
+
+    Ok(())
+}
+
+/// Shares the mapping in `parent` with `child`, copying the page tables
+/// below it; `level` is that of the table holding the entries.
+fn fork_entry(parent: &mut PageTableEntry, child: &mut PageTableEntry, level: u8) -> Option<()> {
+    if parent.is_unused() {
+        return Some(());
+    }
+
+    let mut flags = parent.flags();
+
+    if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+        if flags.contains(OWNED) {
+            let first = PhysFrame::<Size4KiB>::containing_address(parent.addr());
+
+            for i in 0..512u64.pow(u32::from(level) - 1) {
+                frame::share(first + i);
+            }
+
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COW;
+
+                parent.set_flags(flags);
+            }
+        }
+
+        child.set_addr(parent.addr(), flags);
+
+        return Some(());
+    }
+
+    let child_frame = frame::allocate_zeroed()?;
+
+    child.set_frame(child_frame, flags);
+
+    let parent_table = unsafe { table_mut(parent.frame().ok()?) };
+    let child_table = unsafe { table_mut(child_frame) };
+
+    for (parent, child) in parent_table.iter_mut().zip(child_table.iter_mut()) {
+        fork_entry(parent, child, level - 1)?;
+    }
+
+    Some(())
+}
+
+fn active_mapper() -> OffsetPageTable<'static> {
+    let level_4_table = unsafe { table_mut(Cr3::read().0) };
+
+    unsafe { OffsetPageTable::new(level_4_table, phys_to_virt(PhysAddr::zero())) }
+}
+
+/// Frees a user page table at `level` along with everything below it.
+fn free_table(table_frame: PhysFrame, level: u8) {
+    let table = unsafe { table_mut(table_frame) };
+
+    for entry in table.iter_mut().filter(|entry| !entry.is_unused()) {
+        let flags = entry.flags();
+        let frame = PhysFrame::containing_address(entry.addr());
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            if flags.contains(OWNED) {
+                let count = 512usize.pow(u32::from(level) - 1);
+
+                frame::deallocate_contiguous(frame, count);
+            }
+        } else {
+            free_table(frame, level - 1);
+        }
+
+        entry.set_unused();
+    }
+
+    frame::deallocate(table_frame);
+}
+
+fn leaf_flags(mapper: &OffsetPageTable, page: Page) -> Option<PageTableFlags> {
+    let table = mapper.level_4_table();
+
+    let mut entry = &table[page.p4_index()];
+
+    for index in [page.p3_index(), page.p2_index(), page.p1_index()] {
+        if entry.is_unused() {
+            return None;
+        }
+
+        let table = unsafe { table_mut(entry.frame().ok()?) };
+
+        entry = &table[index];
+    }
+
+    (!entry.is_unused()).then(|| entry.flags())
+}
+
+#[must_use]
+pub const fn is_user_range(start: VirtAddr, size: u64) -> bool {
+    start.as_u64() >= USER_START && start.as_u64().saturating_add(size) <= USER_END
+}
+
+fn pages(start: VirtAddr, size: u64) -> impl Iterator<Item = Page> {
+    let end = (start + size).align_up(vmm::PAGE_SIZE);
+
+    Page::range(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    )
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
@@ -0,0 +1,121 @@
+use super::{
+    address_space,
+    frame::{self, GlobalFrameAllocator},
+    supported_flags,
+    vma::{Vma, VmaKind},
+    vmm,
+};
+use core::fmt;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{mapper::MapToError, Mapper, OffsetPageTable, Page, PageTableFlags},
+    },
+    VirtAddr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError {
+    Protection,
+    Unreserved,
+    AccessDenied(VmaKind),
+    OutOfMemory,
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Protection => write!(f, "protection violation"),
+            Self::Unreserved => write!(f, "address not reserved"),
+            Self::AccessDenied(kind) => write!(f, "access not allowed in {kind:?} area"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/// Tries to resolve a page fault by backing a lazily allocated area; an
+/// error means the access was genuinely invalid.
+pub fn handle(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), PageFaultError> {
+    let user = address_space::is_user_range(addr, 1);
+
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return if user && error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            address_space::copy_on_write(addr)
+        } else {
+            Err(PageFaultError::Protection)
+        };
+    }
+
+    if user {
+        address_space::populate(addr, error_code)
+    } else {
+        vmm::populate(addr, error_code)
+    }
+}
+
+/// Maps a zeroed frame for the page containing `addr` inside `area`.
+pub(super) fn populate(
+    mapper: &mut OffsetPageTable,
+    area: &Vma,
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+    extra_flags: PageTableFlags,
+) -> Result<(), PageFaultError> {
+    if !area.allows(error_code) {
+        return Err(PageFaultError::AccessDenied(area.kind));
+    }
+
+    let frame = frame::allocate_zeroed().ok_or(PageFaultError::OutOfMemory)?;
+
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (area.flags & PageTableFlags::USER_ACCESSIBLE);
+
+    let result = unsafe {
+        mapper.map_to_with_table_flags(
+            Page::containing_address(addr),
+            frame,
+            supported_flags(area.flags | extra_flags),
+            parent_flags,
+            &mut GlobalFrameAllocator,
+        )
+    };
+
+    match result {
+        Ok(flush) => {
+            flush.flush();
+
+            Ok(())
+        }
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            frame::deallocate(frame);
+
+            Ok(())
+        }
+        Err(_) => {
+            frame::deallocate(frame);
+
+            Err(PageFaultError::OutOfMemory)
+        }
+    }
+}
+
+/// Unmaps and frees whichever pages of `area` were populated.
+pub(super) fn depopulate(mapper: &mut OffsetPageTable, area: &Vma, flush: bool) {
+    let pages = Page::range(
+        Page::containing_address(area.start),
+        Page::containing_address(area.end.align_up(vmm::PAGE_SIZE)),
+    );
+
+    for page in pages {
+        if let Ok((frame, flusher)) = mapper.unmap(page) {
+            if flush {
+                flusher.flush();
+            } else {
+                flusher.ignore();
+            }
+
+            frame::deallocate(frame);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use super::phys_to_virt;
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+pub const FRAME_SIZE: u64 = 4096;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+static FRAME_ALLOCATOR: OnceCell<Mutex<BitmapFrameAllocator>> = OnceCell::uninit();
+
+pub fn init(memory_regions: &MemoryRegions) {
+    let allocator = unsafe { BitmapFrameAllocator::new(memory_regions) };
+
+    FRAME_ALLOCATOR.init_once(|| Mutex::new(allocator));
+}
+
+fn with_allocator<T>(f: impl FnOnce(&mut BitmapFrameAllocator) -> T) -> T {
+    interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR
+            .try_get()
+            .expect("frame allocator not initialized")
+            .lock();
+
+        f(&mut allocator)
+    })
+}
+
+#[must_use]
+pub fn allocate() -> Option<PhysFrame> {
+    with_allocator(BitmapFrameAllocator::allocate)
+}
+
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn allocate_zeroed() -> Option<PhysFrame> {
+    let frame = allocate()?;
+
+    let ptr = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+
+    unsafe { ptr.write_bytes(0, FRAME_SIZE as usize) };
+
+    Some(frame)
+}
+
+#[must_use]
+pub fn allocate_contiguous(count: usize, align: usize) -> Option<PhysFrame> {
+    with_allocator(|allocator| allocator.allocate_contiguous(count, align))
+}
+
+/// Allocates a frame that lies entirely below `limit`, for hardware that
+/// cannot address all of physical memory.
+#[must_use]
+pub fn allocate_below(limit: PhysAddr) -> Option<PhysFrame> {
+    with_allocator(|allocator| allocator.allocate_below(frame_index(limit.as_u64())))
+}
+
+/// Adds a reference to an allocated frame, which then takes one more
+/// [`deallocate`] to free.
+pub fn share(frame: PhysFrame) {
+    with_allocator(|allocator| allocator.share(frame));
+}
+
+/// How many owners an allocated frame has; zero if it is free.
+#[must_use]
+pub fn references(frame: PhysFrame) -> usize {
+    with_allocator(|allocator| allocator.references(frame))
+}
+
+/// Drops a reference to `frame`, freeing it once nothing else shares it.
+pub fn deallocate(frame: PhysFrame) {
+    with_allocator(|allocator| allocator.deallocate(frame));
+}
+
+pub fn deallocate_contiguous(start: PhysFrame, count: usize) {
+    with_allocator(|allocator| allocator.deallocate_contiguous(start, count));
+}
+
+#[must_use]
+pub fn stats() -> FrameStats {
+    with_allocator(|allocator| allocator.stats())
+}
+
+/// Hands out frames from the global bitmap allocator, for use with the
+/// `x86_64` paging APIs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        deallocate(frame);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+impl FrameStats {
+    #[must_use]
+    pub const fn total_bytes(&self) -> u64 {
+        self.total as u64 * FRAME_SIZE
+    }
+
+    #[must_use]
+    pub const fn used_bytes(&self) -> u64 {
+        self.used as u64 * FRAME_SIZE
+    }
+
+    #[must_use]
+    pub const fn free_bytes(&self) -> u64 {
+        self.free as u64 * FRAME_SIZE
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB used, {} KiB free, {} KiB total",
+            self.used_bytes() / 1024,
+            self.free_bytes() / 1024,
+            self.total_bytes() / 1024
+        )
+    }
+}
+
+/// One bit per physical frame up to the end of the highest usable region;
+/// a set bit means the frame is in use or not usable at all. Frames shared
+/// between several owners also count their extra references.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    shares: &'static mut [u16],
+    frame_count: usize,
+    usable: usize,
+    free: usize,
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// # Safety
+    ///
+    /// The usable regions in `memory_regions` must really be unused, and the
+    /// physical memory offset must already be set.
+    unsafe fn new(memory_regions: &MemoryRegions) -> Self {
+        let usable_regions = || {
+            memory_regions
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+        };
+
+        let max_addr = usable_regions().map(|r| r.end).max().unwrap_or(0);
+
+        let frame_count = frame_index(max_addr);
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (words * core::mem::size_of::<u64>()) as u64;
+        let shares_bytes = (frame_count * core::mem::size_of::<u16>()) as u64;
+        let bitmap_frames = frame_index(align_up(bitmap_bytes + shares_bytes));
+
+        let bitmap_start = usable_regions()
+            .map(|r| (align_up(r.start), r.end))
+            .find(|&(start, end)| start + bitmap_frames as u64 * FRAME_SIZE <= end)
+            .map(|(start, _)| start)
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_ptr = phys_to_virt(PhysAddr::new(bitmap_start)).as_mut_ptr::<u64>();
+        let shares_ptr =
+            phys_to_virt(PhysAddr::new(bitmap_start + bitmap_bytes)).as_mut_ptr::<u16>();
+
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_ptr, words) };
+        let shares = unsafe { core::slice::from_raw_parts_mut(shares_ptr, frame_count) };
+
+        bitmap.fill(u64::MAX);
+        shares.fill(0);
+
+        let mut allocator = Self {
+            bitmap,
+            shares,
+            frame_count,
+            usable: 0,
+            free: 0,
+            next: 0,
+        };
+
+        for region in usable_regions() {
+            let start = frame_index(align_up(region.start));
+            let end = frame_index(region.end);
+
+            for index in start..end {
+                allocator.clear(index);
+                allocator.usable += 1;
+                allocator.free += 1;
+            }
+        }
+
+        // the bitmap and share counts live in the memory they describe
+        let bitmap_index = frame_index(bitmap_start);
+        for index in bitmap_index..bitmap_index + bitmap_frames {
+            allocator.mark_used(index);
+        }
+
+        // keep the null frame out of circulation
+        allocator.mark_used(0);
+
+        allocator
+    }
+
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        if self.free == 0 {
+            return None;
+        }
+
+        let words = self.bitmap.len();
+        let start_word = self.next / BITS_PER_WORD;
+
+        for offset in 0..words {
+            let word_index = (start_word + offset) % words;
+            let word = self.bitmap[word_index];
+
+            if word == u64::MAX {
+                continue;
+            }
+
+            let index = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+
+            self.mark_used(index);
+            self.next = index;
+
+            return Some(frame_at(index));
+        }
+
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames, with the first one
+    /// aligned to `align` frames.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let mut start = 0;
+
+        while start + count <= self.frame_count {
+            if let Some(used) = (start..start + count).find(|&index| self.is_used(index)) {
+                start = (used + 1).next_multiple_of(align);
+                continue;
+            }
+
+            for index in start..start + count {
+                self.mark_used(index);
+            }
+
+            return Some(frame_at(start));
+        }
+
+        None
+    }
+
+    /// Allocates the lowest free frame, if its index is below `limit`.
+    pub fn allocate_below(&mut self, limit: usize) -> Option<PhysFrame> {
+        let index = (0..limit.min(self.frame_count)).find(|&index| !self.is_used(index))?;
+
+        self.mark_used(index);
+
+        Some(frame_at(index))
+    }
+
+    pub fn share(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame.start_address().as_u64());
+
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "frame {:#x} shared but not allocated",
+            frame.start_address().as_u64()
+        );
+
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("too many references to one frame");
+    }
+
+    #[must_use]
+    pub fn references(&self, frame: PhysFrame) -> usize {
+        let index = frame_index(frame.start_address().as_u64());
+
+        if index < self.frame_count && self.is_used(index) {
+            usize::from(self.shares[index]) + 1
+        } else {
+            0
+        }
+    }
+
+    pub fn deallocate(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame.start_address().as_u64());
+
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "frame {:#x} freed but not allocated",
+            frame.start_address().as_u64()
+        );
+
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+
+            return;
+        }
+
+        self.clear(index);
+        self.free += 1;
+
+        if index < self.next {
+            self.next = index;
+        }
+    }
+
+    pub fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        for frame in PhysFrame::range(start, start + count as u64) {
+            self.deallocate(frame);
+        }
+    }
+
+    #[must_use]
+    pub const fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.usable,
+            used: self.usable - self.free,
+            free: self.free,
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+            self.free -= 1;
+        }
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn frame_index(addr: u64) -> usize {
+    (addr / FRAME_SIZE) as usize
+}
+
+const fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+const fn align_up(addr: u64) -> u64 {
+    addr.next_multiple_of(FRAME_SIZE)
+}
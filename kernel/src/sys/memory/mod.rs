@@ -0,0 +1,48 @@
+use super::{allocator, cpu};
+use crate::log;
+use bootloader_api::info::MemoryRegions;
+use x86_64::{instructions::interrupts, structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+pub mod address_space;
+pub mod fault;
+pub mod frame;
+pub mod stack;
+pub mod user;
+pub mod vma;
+pub mod vmm;
+
+pub static mut PHYS_MEM_OFFSET: u64 = 0;
+
+pub fn init(physical_memory_offset: u64, memory_regions: &'static MemoryRegions) {
+    interrupts::without_interrupts(|| {
+        unsafe {
+            PHYS_MEM_OFFSET = physical_memory_offset;
+        }
+
+        frame::init(memory_regions);
+        vmm::init();
+        address_space::init();
+
+        allocator::init_heap().expect("heap initialization failed");
+    });
+
+    log!("memory initialized");
+    log!("physical memory {}", frame::stats());
+    log!("{}", vmm::stats());
+    log!("{}", allocator::usage());
+}
+
+#[must_use]
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + unsafe { PHYS_MEM_OFFSET })
+}
+
+/// Drops `NO_EXECUTE` on CPUs without it, where the bit is reserved.
+#[must_use]
+pub fn supported_flags(flags: PageTableFlags) -> PageTableFlags {
+    if cpu::has_nx() {
+        flags
+    } else {
+        flags - PageTableFlags::NO_EXECUTE
+    }
+}
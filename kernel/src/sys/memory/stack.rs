@@ -0,0 +1,81 @@
+use super::vmm::{self, PAGE_SIZE};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// Guard pages of every kernel stack, so a fault in one can be told apart
+/// from any other bad access.
+static GUARDS: Mutex<Vec<(VirtAddr, &'static str)>> = Mutex::new(Vec::new());
+
+/// A kernel stack with an unmapped guard page beneath it, so running off the
+/// bottom faults instead of overwriting whatever lies below. Dropping it
+/// leaves the stack mapped; see [`KernelStack::free`].
+#[derive(Debug)]
+pub struct KernelStack {
+    name: &'static str,
+    guard: VirtAddr,
+    size: u64,
+}
+
+impl KernelStack {
+    pub fn new(name: &'static str, size: u64) -> Result<Self, MapToError<Size4KiB>> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+
+        let guard =
+            vmm::allocate_range(PAGE_SIZE + size).ok_or(MapToError::FrameAllocationFailed)?;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        vmm::map_range(guard + PAGE_SIZE, size, flags)?;
+
+        interrupts::without_interrupts(|| GUARDS.lock().push((guard, name)));
+
+        Ok(Self { name, guard, size })
+    }
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[must_use]
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard + PAGE_SIZE
+    }
+
+    #[must_use]
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + self.size
+    }
+
+    /// Unmaps the stack and frees its frames.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may be running on the stack or referencing it anymore.
+    pub unsafe fn free(self) {
+        interrupts::without_interrupts(|| {
+            GUARDS.lock().retain(|&(guard, _)| guard != self.guard);
+        });
+
+        unsafe { vmm::unmap_range(self.bottom(), self.size).expect("kernel stack not mapped") };
+    }
+}
+
+/// The name of the stack whose guard page contains `addr`, if any.
+///
+/// Does not wait for the lock, as it is called from fault handlers that may
+/// have interrupted its holder.
+#[must_use]
+pub fn guard_hit(addr: VirtAddr) -> Option<&'static str> {
+    let guards = GUARDS.try_lock()?;
+
+    guards
+        .iter()
+        .find(|&&(guard, _)| (guard..guard + PAGE_SIZE).contains(&addr))
+        .map(|&(_, name)| name)
+}
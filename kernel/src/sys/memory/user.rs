@@ -0,0 +1,89 @@
+use super::address_space;
+use crate::sys::cpu;
+use core::fmt;
+use x86_64::VirtAddr;
+
+/// A user pointer that does not point at memory the process could access
+/// itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAddress(pub VirtAddr);
+
+impl fmt::Display for BadAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad user address {:?}", self.0)
+    }
+}
+
+/// Copies `dst.len()` bytes from user memory at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), BadAddress> {
+    check(src, dst.len(), false)?;
+
+    let src = src.as_ptr::<u8>();
+
+    cpu::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), dst.len());
+    });
+
+    Ok(())
+}
+
+/// Copies `src` into user memory at `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), BadAddress> {
+    check(dst, src.len(), true)?;
+
+    let dst = dst.as_mut_ptr::<u8>();
+
+    cpu::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len());
+    });
+
+    Ok(())
+}
+
+/// Types that any bytes are a valid value of, and that have no padding, so
+/// they can be copied from and to user memory as they are.
+pub trait Plain: Copy + sealed::Sealed {}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_plain {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl Plain for $ty {}
+        )*
+    };
+}
+
+impl_plain!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl<const N: usize> sealed::Sealed for [u8; N] {}
+impl<const N: usize> Plain for [u8; N] {}
+
+pub fn read<T: Plain>(src: VirtAddr) -> Result<T, BadAddress> {
+    check(src, core::mem::size_of::<T>(), false)?;
+
+    let src = src.as_ptr::<T>();
+
+    Ok(cpu::with_user_access(|| unsafe { src.read_unaligned() }))
+}
+
+pub fn write<T: Plain>(dst: VirtAddr, value: T) -> Result<(), BadAddress> {
+    check(dst, core::mem::size_of::<T>(), true)?;
+
+    let dst = dst.as_mut_ptr::<T>();
+
+    cpu::with_user_access(|| unsafe { dst.write_unaligned(value) });
+
+    Ok(())
+}
+
+fn check(addr: VirtAddr, size: usize, write: bool) -> Result<(), BadAddress> {
+    if address_space::is_accessible(addr, size as u64, write) {
+        Ok(())
+    } else {
+        Err(BadAddress(addr))
+    }
+}
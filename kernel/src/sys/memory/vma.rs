@@ -0,0 +1,122 @@
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::{
+    structures::{idt::PageFaultErrorCode, paging::PageTableFlags},
+    VirtAddr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    Anonymous,
+    Heap,
+    Stack,
+}
+
+/// A reserved range of address space whose pages are only backed by frames
+/// once they are first touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    #[must_use]
+    pub fn new(start: VirtAddr, size: u64, flags: PageTableFlags, kind: VmaKind) -> Self {
+        Self {
+            start,
+            end: start + size,
+            flags: flags | PageTableFlags::PRESENT,
+            kind,
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Whether the access described by a page fault is allowed in this area.
+    #[must_use]
+    pub const fn allows(&self, error_code: PageFaultErrorCode) -> bool {
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let fetch = error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+        let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+
+        (!write || self.flags.contains(PageTableFlags::WRITABLE))
+            && (!fetch || !self.flags.contains(PageTableFlags::NO_EXECUTE))
+            && (!user || self.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverlapError(pub Vma);
+
+impl fmt::Display for OverlapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "overlaps {:?} area {:?}..{:?}",
+            self.0.kind, self.0.start, self.0.end
+        )
+    }
+}
+
+/// The areas of one address space, kept sorted by start address.
+#[derive(Debug, Clone, Default)]
+pub struct Areas {
+    areas: Vec<Vma>,
+}
+
+impl Areas {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { areas: Vec::new() }
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), OverlapError> {
+        let index = self.areas.partition_point(|area| area.start < vma.start);
+
+        let neighbours = [index.checked_sub(1), Some(index)];
+
+        for area in neighbours
+            .into_iter()
+            .flatten()
+            .filter_map(|i| self.areas.get(i))
+        {
+            if area.start < vma.end && vma.start < area.end {
+                return Err(OverlapError(*area));
+            }
+        }
+
+        self.areas.insert(index, vma);
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        let index = self.areas.iter().position(|area| area.start == start)?;
+
+        Some(self.areas.remove(index))
+    }
+
+    #[must_use]
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        let index = self.areas.partition_point(|area| area.start <= addr);
+
+        self.areas[..index]
+            .last()
+            .filter(|area| area.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter()
+    }
+}
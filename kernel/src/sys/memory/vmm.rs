@@ -0,0 +1,561 @@
+use super::{
+    fault::{self, PageFaultError},
+    frame::{self, GlobalFrameAllocator},
+    phys_to_virt, supported_flags,
+    vma::{Areas, OverlapError, Vma, VmaKind},
+};
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+            Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+            Size2MiB, Size4KiB, Translate,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+pub const PAGE_SIZE: u64 = 4096;
+pub const HUGE_PAGE_SIZE: u64 = Size2MiB::SIZE;
+
+#[allow(clippy::cast_possible_truncation)]
+const FRAMES_PER_HUGE_PAGE: usize = (HUGE_PAGE_SIZE / PAGE_SIZE) as usize;
+
+/// Uncached device memory handed out by [`map_mmio`].
+pub const MMIO_START: u64 = 0x_4460_0000_0000;
+pub const MMIO_END: u64 = 0x_4470_0000_0000;
+
+/// Kernel virtual ranges handed out by [`allocate_range`].
+pub const KERNEL_RANGES_START: u64 = 0x_4470_0000_0000;
+pub const KERNEL_RANGES_END: u64 = 0x_4480_0000_0000;
+
+static MAPPER: OnceCell<Mutex<OffsetPageTable<'static>>> = OnceCell::uninit();
+
+static KERNEL_AREAS: Mutex<Areas> = Mutex::new(Areas::new());
+
+static SMALL_MAPPINGS: AtomicUsize = AtomicUsize::new(0);
+static HUGE_MAPPINGS: AtomicUsize = AtomicUsize::new(0);
+
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+static NEXT_KERNEL_RANGE: AtomicU64 = AtomicU64::new(KERNEL_RANGES_START);
+
+pub fn init() {
+    let level_4_table = unsafe { active_level_4_table() };
+
+    let mapper = unsafe { OffsetPageTable::new(level_4_table, phys_to_virt(PhysAddr::zero())) };
+
+    MAPPER.init_once(|| Mutex::new(mapper));
+}
+
+pub(super) fn with_mapper<T>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> T) -> T {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.try_get().expect("vmm not initialized").lock();
+
+        f(&mut mapper)
+    })
+}
+
+/// Backs `size` bytes at `start` with freshly allocated frames, using 2 MiB
+/// pages wherever the range and physical memory allow it. Nothing is left
+/// mapped if this fails part way through.
+pub fn map_range(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let size = size.next_multiple_of(PAGE_SIZE);
+
+    with_mapper(|mapper| {
+        let mut offset = 0;
+
+        while offset < size {
+            let addr = start + offset;
+
+            if addr.is_aligned(HUGE_PAGE_SIZE)
+                && size - offset >= HUGE_PAGE_SIZE
+                && map_huge_page(mapper, addr, flags)
+            {
+                offset += HUGE_PAGE_SIZE;
+                continue;
+            }
+
+            let result = frame::allocate()
+                .ok_or(MapToError::FrameAllocationFailed)
+                .and_then(|frame| {
+                    let result =
+                        unsafe { map_page(mapper, Page::containing_address(addr), frame, flags) };
+
+                    if result.is_err() {
+                        frame::deallocate(frame);
+                    }
+
+                    result
+                });
+
+            if let Err(err) = result {
+                let _ = unmap_pages(mapper, start, offset, true);
+
+                return Err(err);
+            }
+
+            offset += PAGE_SIZE;
+        }
+
+        Ok(())
+    })
+}
+
+/// Maps `size` bytes at `start` onto the physical range at `phys`.
+///
+/// # Safety
+///
+/// The physical range must not be memory the kernel uses for anything else,
+/// or aliasing it must be harmless.
+pub unsafe fn map_range_to(
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper| {
+        let first = PhysFrame::<Size4KiB>::containing_address(phys);
+
+        for (i, page) in pages(start, size).enumerate() {
+            let frame = first + i as u64;
+
+            if let Err(err) = unsafe { map_page(mapper, page, frame, flags) } {
+                let _ = unmap_pages(mapper, start, i as u64 * PAGE_SIZE, false);
+
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Unmaps `size` bytes at `start` and returns their frames to the frame
+/// allocator.
+///
+/// # Safety
+///
+/// The range must have been mapped with [`map_range`] and nothing may still
+/// reference it.
+pub unsafe fn unmap_range(start: VirtAddr, size: u64) -> Result<(), UnmapError> {
+    with_mapper(|mapper| unmap_pages(mapper, start, size, true))
+}
+
+/// Changes the flags of every page in the range; `PRESENT` is always kept.
+pub fn protect(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    let end = start + size;
+    let flags = supported_flags(flags);
+
+    with_mapper(|mapper| {
+        let mut addr = start.align_down(PAGE_SIZE);
+
+        while addr < end {
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                allow_user_access(mapper, Page::containing_address(addr));
+            }
+
+            if let TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } = mapper.translate(addr)
+            {
+                let page = Page::<Size2MiB>::containing_address(addr);
+
+                if !covers(start, end, page) {
+                    if !split_huge_page(mapper, page) {
+                        return Err(FlagUpdateError::ParentEntryHugePage);
+                    }
+
+                    continue;
+                }
+
+                unsafe {
+                    mapper
+                        .update_flags(
+                            page,
+                            flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE,
+                        )?
+                        .flush();
+                }
+
+                addr = page.start_address() + HUGE_PAGE_SIZE;
+            } else {
+                let page = Page::<Size4KiB>::containing_address(addr);
+
+                unsafe {
+                    mapper
+                        .update_flags(page, flags | PageTableFlags::PRESENT)?
+                        .flush();
+                }
+
+                addr += PAGE_SIZE;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[must_use]
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+/// Maps a device's registers into the MMIO window with caching disabled and
+/// returns the virtual address matching `phys`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let offset = phys.as_u64() % PAGE_SIZE;
+    let size = (offset + size).next_multiple_of(PAGE_SIZE);
+
+    let start = NEXT_MMIO.fetch_add(size, Ordering::Relaxed);
+    assert!(start + size <= MMIO_END, "mmio window exhausted");
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    let start = VirtAddr::new(start);
+
+    unsafe { map_range_to(start, phys.align_down(PAGE_SIZE), size, flags)? };
+
+    Ok(start + offset)
+}
+
+/// Unmaps a region returned by [`map_mmio`] without touching the frames.
+///
+/// # Safety
+///
+/// Nothing may still reference the mapping.
+pub unsafe fn unmap_mmio(start: VirtAddr, size: u64) -> Result<(), UnmapError> {
+    let offset = start.as_u64() % PAGE_SIZE;
+
+    with_mapper(|mapper| {
+        unmap_pages(
+            mapper,
+            start.align_down(PAGE_SIZE),
+            (offset + size).next_multiple_of(PAGE_SIZE),
+            false,
+        )
+    })
+}
+
+/// Reserves (but does not map) a page aligned range of kernel address space.
+#[must_use]
+pub fn allocate_range(size: u64) -> Option<VirtAddr> {
+    allocate_aligned_range(size, PAGE_SIZE)
+}
+
+/// Like [`allocate_range`], with the start aligned to `align` bytes.
+#[must_use]
+pub fn allocate_aligned_range(size: u64, align: u64) -> Option<VirtAddr> {
+    let size = size.next_multiple_of(PAGE_SIZE);
+
+    let mut start = 0;
+
+    NEXT_KERNEL_RANGE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            start = next.next_multiple_of(align);
+
+            Some(start + size)
+        })
+        .ok()?;
+
+    (start + size <= KERNEL_RANGES_END).then(|| VirtAddr::new(start))
+}
+
+/// Maps a buffer of at least `size` bytes somewhere in kernel address space.
+/// Buffers of 2 MiB or more are aligned so they can use huge pages.
+pub fn map_buffer(size: u64, flags: PageTableFlags) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let align = if size >= HUGE_PAGE_SIZE {
+        HUGE_PAGE_SIZE
+    } else {
+        PAGE_SIZE
+    };
+
+    let start = allocate_aligned_range(size, align).ok_or(MapToError::FrameAllocationFailed)?;
+
+    map_range(start, size, flags)?;
+
+    Ok(start)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingStats {
+    pub small: usize,
+    pub huge: usize,
+}
+
+impl fmt::Display for MappingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} 4 KiB pages and {} 2 MiB pages mapped",
+            self.small, self.huge
+        )
+    }
+}
+
+/// How many pages of each size the kernel has mapped through the vmm.
+#[must_use]
+pub fn stats() -> MappingStats {
+    MappingStats {
+        small: SMALL_MAPPINGS.load(Ordering::Relaxed),
+        huge: HUGE_MAPPINGS.load(Ordering::Relaxed),
+    }
+}
+
+/// Reserves a kernel range that is backed page by page as it is touched.
+pub fn reserve(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    kind: VmaKind,
+) -> Result<(), OverlapError> {
+    let vma = Vma::new(start, size, flags, kind);
+
+    interrupts::without_interrupts(|| KERNEL_AREAS.lock().insert(vma))
+}
+
+/// Like [`allocate_range`], but the range is backed lazily on first use.
+#[must_use]
+pub fn reserve_range(size: u64, flags: PageTableFlags, kind: VmaKind) -> Option<VirtAddr> {
+    let start = allocate_range(size)?;
+
+    reserve(start, size.next_multiple_of(PAGE_SIZE), flags, kind).ok()?;
+
+    Some(start)
+}
+
+/// Drops a reserved kernel range along with any frames backing it.
+///
+/// # Safety
+///
+/// Nothing may still reference the range.
+#[must_use]
+pub unsafe fn release(start: VirtAddr) -> Option<Vma> {
+    let vma = interrupts::without_interrupts(|| KERNEL_AREAS.lock().remove(start))?;
+
+    with_mapper(|mapper| fault::depopulate(mapper, &vma, true));
+
+    Some(vma)
+}
+
+pub(super) fn populate(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), PageFaultError> {
+    let area = *KERNEL_AREAS
+        .lock()
+        .find(addr)
+        .ok_or(PageFaultError::Unreserved)?;
+
+    with_mapper(|mapper| fault::populate(mapper, &area, addr, error_code, PageTableFlags::empty()))
+}
+
+unsafe fn map_page(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = supported_flags(flags);
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+    unsafe {
+        mapper
+            .map_to_with_table_flags(page, frame, flags, parent_flags, &mut GlobalFrameAllocator)?
+            .flush();
+    }
+
+    SMALL_MAPPINGS.fetch_add(1, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Tries to back the 2 MiB page at `addr` with one contiguous run of frames,
+/// returning whether it worked so the caller can fall back to small pages.
+fn map_huge_page(mapper: &mut OffsetPageTable, addr: VirtAddr, flags: PageTableFlags) -> bool {
+    let Some(first) = frame::allocate_contiguous(FRAMES_PER_HUGE_PAGE, FRAMES_PER_HUGE_PAGE) else {
+        return false;
+    };
+
+    let page = Page::<Size2MiB>::containing_address(addr);
+    let frame = PhysFrame::<Size2MiB>::containing_address(first.start_address());
+
+    let flags = supported_flags(flags);
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+    let result = unsafe {
+        mapper.map_to_with_table_flags(page, frame, flags, parent_flags, &mut GlobalFrameAllocator)
+    };
+
+    let Ok(flush) = result else {
+        frame::deallocate_contiguous(first, FRAMES_PER_HUGE_PAGE);
+
+        return false;
+    };
+
+    flush.flush();
+
+    HUGE_MAPPINGS.fetch_add(1, Ordering::Relaxed);
+
+    true
+}
+
+fn unmap_pages(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    free_frames: bool,
+) -> Result<(), UnmapError> {
+    let end = start + size;
+    let mut addr = start.align_down(PAGE_SIZE);
+
+    while addr < end {
+        if let TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(_),
+            ..
+        } = mapper.translate(addr)
+        {
+            let page = Page::<Size2MiB>::containing_address(addr);
+
+            if !covers(start, end, page) {
+                if !split_huge_page(mapper, page) {
+                    return Err(UnmapError::ParentEntryHugePage);
+                }
+
+                continue;
+            }
+
+            let (frame, flush) = mapper.unmap(page)?;
+
+            flush.flush();
+
+            HUGE_MAPPINGS.fetch_sub(1, Ordering::Relaxed);
+
+            if free_frames {
+                frame::deallocate_contiguous(
+                    PhysFrame::containing_address(frame.start_address()),
+                    FRAMES_PER_HUGE_PAGE,
+                );
+            }
+
+            addr = page.start_address() + HUGE_PAGE_SIZE;
+        } else {
+            let (frame, flush) = mapper.unmap(Page::<Size4KiB>::containing_address(addr))?;
+
+            flush.flush();
+
+            SMALL_MAPPINGS.fetch_sub(1, Ordering::Relaxed);
+
+            if free_frames {
+                frame::deallocate(frame);
+            }
+
+            addr += PAGE_SIZE;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the page-aligned range around `start..end` takes up all of `page`.
+fn covers(start: VirtAddr, end: VirtAddr, page: Page<Size2MiB>) -> bool {
+    page.start_address() >= start.align_down(PAGE_SIZE)
+        && page.start_address() + HUGE_PAGE_SIZE <= end.align_up(PAGE_SIZE)
+}
+
+/// Remaps the 2 MiB page at `page` with 4 KiB pages onto the same frames, so
+/// that part of it can be unmapped or reflagged. Returns whether it worked;
+/// it only fails when there is no frame for the new table.
+fn split_huge_page(mapper: &mut OffsetPageTable, page: Page<Size2MiB>) -> bool {
+    let mut table: &mut PageTable = mapper.level_4_table_mut();
+
+    for index in [page.p4_index(), page.p3_index()] {
+        table = unsafe { &mut *phys_to_virt(table[index].addr()).as_mut_ptr::<PageTable>() };
+    }
+
+    let entry = &mut table[page.p2_index()];
+
+    let Some(table_frame) = frame::allocate() else {
+        return false;
+    };
+
+    let small_table =
+        unsafe { &mut *phys_to_virt(table_frame.start_address()).as_mut_ptr::<PageTable>() };
+
+    let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+
+    for (i, small) in small_table.iter_mut().enumerate() {
+        small.set_addr(entry.addr() + i as u64 * PAGE_SIZE, flags);
+    }
+
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+    entry.set_frame(table_frame, parent_flags);
+
+    // drops the whole 2 MiB translation
+    tlb::flush(page.start_address());
+
+    HUGE_MAPPINGS.fetch_sub(1, Ordering::Relaxed);
+    SMALL_MAPPINGS.fetch_add(FRAMES_PER_HUGE_PAGE, Ordering::Relaxed);
+
+    true
+}
+
+/// Sets `USER_ACCESSIBLE` on the table entries leading to `page`, which the
+/// leaf flag alone is not enough for.
+fn allow_user_access(mapper: &mut OffsetPageTable, page: Page) {
+    let mut table: &mut PageTable = mapper.level_4_table_mut();
+
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &mut table[index];
+
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return;
+        }
+
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+
+        table = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+    }
+}
+
+fn pages(start: VirtAddr, size: u64) -> impl Iterator<Item = Page> {
+    let end = (start + size).align_up(PAGE_SIZE);
+
+    Page::range(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    )
+}
+
+unsafe fn active_level_4_table() -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let virt = phys_to_virt(level_4_table_frame.start_address());
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    unsafe { &mut *page_table_ptr }
+}
@@ -1,17 +1,26 @@
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
 pub mod ata;
 pub mod clock;
+pub mod clocksource;
 pub mod cmos;
 pub mod cpu;
 pub mod framebuffer;
 pub mod gdt;
+pub mod hpet;
 pub mod idt;
 pub mod memory;
 pub mod pic;
+pub mod power;
+pub mod process;
 pub mod serial;
+pub mod smp;
 pub mod syscall;
 pub mod task;
+pub mod thread;
 pub mod time;
+pub mod timer;
 
 #[macro_export]
 macro_rules! log {
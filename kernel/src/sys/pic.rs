@@ -1,11 +1,20 @@
+use super::apic;
 use crate::log;
 use pic8259::ChainedPics;
 use spin::Mutex;
 
 pub fn init() {
+    // remapped even when unused, so stray interrupts do not look like
+    // exceptions
     unsafe { PICS.lock().initialize() };
 
-    log!("pic initialized");
+    if apic::init() {
+        unsafe { PICS.lock().disable() };
+
+        log!("pic disabled");
+    } else {
+        log!("pic initialized");
+    }
 
     x86_64::instructions::interrupts::enable();
 
@@ -0,0 +1,223 @@
+use super::{
+    acpi::{self, fadt::Fadt, GenericAddress, SdtHeader},
+    memory::{phys_to_virt, vmm},
+};
+use crate::log;
+use x86_64::{
+    instructions::{hlt, interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
+
+const SCI_EN: u16 = 1;
+const SLP_EN: u16 = 1 << 13;
+const SLP_TYP_SHIFT: u16 = 10;
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+
+/// Powers the machine off through the ACPI S5 sleep state, halting forever
+/// if that is not possible.
+pub fn shutdown() -> ! {
+    log!("shutting down");
+
+    interrupts::disable();
+
+    if let Some(fadt) = acpi::fadt::parse() {
+        if let Some((slp_typ_a, slp_typ_b)) = s5_sleep_types(&fadt) {
+            enable_acpi(&fadt);
+
+            unsafe { enter_sleep_state(&fadt, slp_typ_a, slp_typ_b) };
+        }
+    }
+
+    log!("acpi shutdown failed, halting");
+
+    loop {
+        hlt();
+    }
+}
+
+/// Restarts the machine through the ACPI reset register, then the keyboard
+/// controller, and finally by triple faulting.
+pub fn reboot() -> ! {
+    log!("rebooting");
+
+    interrupts::disable();
+
+    if let Some(fadt) = acpi::fadt::parse() {
+        if let Some(reset) = fadt.reset_register {
+            unsafe { write_generic_address(reset, fadt.reset_value) };
+        }
+    }
+
+    unsafe { reset_keyboard_controller() };
+
+    triple_fault()
+}
+
+/// Reads `SLP_TYPa` and `SLP_TYPb` out of the `_S5_` package in the DSDT.
+fn s5_sleep_types(fadt: &Fadt) -> Option<(u16, u16)> {
+    let header = unsafe {
+        phys_to_virt(fadt.dsdt)
+            .as_ptr::<SdtHeader>()
+            .read_unaligned()
+    };
+
+    let aml = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(fadt.dsdt).as_ptr::<u8>(),
+            header.length as usize,
+        )
+    };
+
+    let start = aml
+        .windows(4)
+        .enumerate()
+        .skip(core::mem::size_of::<SdtHeader>())
+        .find(|&(i, name)| {
+            name == b"_S5_"
+                && (aml[i - 1] == AML_NAME_OP || (aml[i - 2] == AML_NAME_OP && aml[i - 1] == b'\\'))
+        })
+        .map(|(i, _)| i + 4)?;
+
+    let mut bytes = aml.get(start..)?.iter().copied();
+
+    if bytes.next()? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    // the top two bits of the package length say how many more bytes follow
+    let package_length = bytes.next()?;
+    for _ in 0..(package_length >> 6) {
+        bytes.next()?;
+    }
+
+    let _element_count = bytes.next()?;
+
+    let mut integer = || {
+        let byte = bytes.next()?;
+
+        if byte == AML_BYTE_PREFIX {
+            bytes.next()
+        } else {
+            Some(byte)
+        }
+    };
+
+    let slp_typ_a = integer()?;
+    let slp_typ_b = integer().unwrap_or(0);
+
+    Some((u16::from(slp_typ_a), u16::from(slp_typ_b)))
+}
+
+/// Hands power management from the firmware to the OS, if it still owns it.
+fn enable_acpi(fadt: &Fadt) {
+    let Ok(control_port) = u16::try_from(fadt.pm1a_control_block) else {
+        return;
+    };
+
+    let mut control: Port<u16> = Port::new(control_port);
+
+    if unsafe { control.read() } & SCI_EN != 0
+        || fadt.smi_command_port == 0
+        || fadt.acpi_enable == 0
+    {
+        return;
+    }
+
+    let Ok(smi_command_port) = u16::try_from(fadt.smi_command_port) else {
+        return;
+    };
+
+    unsafe { Port::<u8>::new(smi_command_port).write(fadt.acpi_enable) };
+
+    for _ in 0..1_000_000 {
+        if unsafe { control.read() } & SCI_EN != 0 {
+            return;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn enter_sleep_state(fadt: &Fadt, slp_typ_a: u16, slp_typ_b: u16) {
+    for (block, slp_typ) in [
+        (fadt.pm1a_control_block, slp_typ_a),
+        (fadt.pm1b_control_block, slp_typ_b),
+    ] {
+        let Ok(port) = u16::try_from(block) else {
+            continue;
+        };
+
+        if port == 0 {
+            continue;
+        }
+
+        let mut control: Port<u16> = Port::new(port);
+
+        let value = unsafe { control.read() } & !(0b111 << SLP_TYP_SHIFT);
+
+        unsafe { control.write(value | (slp_typ << SLP_TYP_SHIFT) | SLP_EN) };
+    }
+
+    // the machine may take a moment to actually turn off
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn write_generic_address(register: GenericAddress, value: u8) {
+    match register.address_space {
+        GenericAddress::SYSTEM_IO => {
+            if let Ok(port) = u16::try_from(register.address) {
+                unsafe { Port::<u8>::new(port).write(value) };
+            }
+        }
+        GenericAddress::SYSTEM_MEMORY => {
+            if let Ok(addr) = vmm::map_mmio(PhysAddr::new(register.address), 1) {
+                unsafe { addr.as_mut_ptr::<u8>().write_volatile(value) };
+            }
+        }
+        _ => {}
+    }
+}
+
+unsafe fn reset_keyboard_controller() {
+    let mut command: Port<u8> = Port::new(KEYBOARD_CONTROLLER_COMMAND);
+
+    for _ in 0..1_000_000 {
+        if unsafe { command.read() } & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+            break;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    unsafe { command.write(KEYBOARD_CONTROLLER_RESET) };
+
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Loads an empty IDT so the next exception cannot be delivered at all.
+fn triple_fault() -> ! {
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+
+    unsafe { lidt(&idt) };
+
+    interrupts::int3();
+
+    loop {
+        hlt();
+    }
+}
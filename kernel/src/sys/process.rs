@@ -0,0 +1,374 @@
+use super::{
+    clock::{self, Duration, Instant},
+    gdt::GDT,
+    idt::Registers,
+    memory::address_space::{self, AddressSpace},
+};
+use crate::log;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    arch::naked_asm,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, structures::idt::InterruptStackFrame, VirtAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub usize);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+        Self(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(usize),
+    Killed,
+}
+
+/// User register state, laid out for [`enter_user`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Context {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rsp: u64,
+    rflags: u64,
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pid: Pid,
+    address_space: AddressSpace,
+    context: Context,
+}
+
+impl Process {
+    /// A process that starts at `entry` with its stack pointer at
+    /// `stack_top`.
+    #[must_use]
+    pub fn new(address_space: AddressSpace, entry: VirtAddr, stack_top: VirtAddr) -> Self {
+        Self {
+            pid: Pid::new(),
+            address_space,
+            context: Context {
+                rip: entry.as_u64(),
+                rsp: stack_top.as_u64(),
+                rflags: 0x202,
+                ..Context::default()
+            },
+        }
+    }
+
+    #[must_use]
+    pub const fn pid(&self) -> Pid {
+        self.pid
+    }
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Kernel stack pointer saved by [`enter_user`], resumed when the process ends.
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+
+static CURRENT: Mutex<Option<Process>> = Mutex::new(None);
+
+/// Forked processes waiting for their turn.
+static READY: Mutex<VecDeque<Process>> = Mutex::new(VecDeque::new());
+
+/// The user state at the start of the syscall being handled.
+static SYSCALL_CONTEXT: Mutex<Option<Context>> = Mutex::new(None);
+
+/// Processes waiting in [`sleep`], with the time to resume them.
+static SLEEPING: Mutex<Vec<(Instant, Process)>> = Mutex::new(Vec::new());
+
+/// Why the running process handed control back to [`run`].
+#[derive(Debug, Clone, Copy)]
+enum Stop {
+    Ended(ExitStatus),
+    Sleeping(Instant),
+}
+
+static STOP: Mutex<Option<Stop>> = Mutex::new(None);
+
+/// Runs `process` and every process it forked, one at a time, until all of
+/// them end. Returns the exit status of `process` itself.
+///
+/// Sleeping processes make way for the others, and the CPU halts when all of
+/// them sleep.
+pub fn run(process: Process) -> ExitStatus {
+    assert!(
+        !RUNNING.swap(true, Ordering::SeqCst),
+        "a process is already running"
+    );
+
+    let pid = process.pid;
+    let mut status = None;
+    let mut next = Some(process);
+
+    while let Some(process) = next {
+        let (id, exit) = run_one(process);
+
+        if let Some(exit) = exit {
+            log!("process {id} ended: {exit:?}");
+
+            if id == pid {
+                status = Some(exit);
+            }
+        }
+
+        next = next_process();
+    }
+
+    RUNNING.store(false, Ordering::SeqCst);
+
+    status.unwrap()
+}
+
+/// The next ready process, waiting for a sleeping one if there is none.
+fn next_process() -> Option<Process> {
+    loop {
+        let (next, wake) = interrupts::without_interrupts(|| {
+            let now = Instant::now();
+
+            let mut ready = READY.lock();
+            let mut sleeping = SLEEPING.lock();
+
+            while let Some(index) = sleeping.iter().position(|&(deadline, _)| deadline <= now) {
+                ready.push_back(sleeping.swap_remove(index).1);
+            }
+
+            let wake = sleeping.iter().map(|&(deadline, _)| deadline).min();
+
+            (ready.pop_front(), wake)
+        });
+
+        match (next, wake) {
+            (Some(process), _) => return Some(process),
+            (None, Some(deadline)) => clock::sleep_until(deadline),
+            (None, None) => return None,
+        }
+    }
+}
+
+/// Runs a process until it stops, returning its exit status unless it only
+/// went to sleep.
+fn run_one(process: Process) -> (Pid, Option<ExitStatus>) {
+    let pid = process.pid;
+    let context = process.context;
+
+    let code = u64::from(GDT.1.user_code.0);
+    let data = u64::from(GDT.1.user_data.0);
+
+    unsafe { process.address_space.activate() };
+
+    interrupts::without_interrupts(|| *CURRENT.lock() = Some(process));
+
+    unsafe { enter_user(&raw const context, KERNEL_RSP.as_ptr(), code, data) };
+
+    unsafe { address_space::activate_kernel() };
+
+    interrupts::without_interrupts(|| {
+        let process = CURRENT.lock().take().expect("no process is running");
+
+        match STOP.lock().take().expect("process stopped without status") {
+            Stop::Ended(status) => {
+                drop(process);
+
+                (pid, Some(status))
+            }
+            Stop::Sleeping(deadline) => {
+                SLEEPING.lock().push((deadline, process));
+
+                (pid, None)
+            }
+        }
+    })
+}
+
+#[must_use]
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Records the user state on syscall entry so [`fork`] can duplicate it.
+pub fn save_context(stack_frame: &InterruptStackFrame, regs: &Registers) {
+    if !is_running() {
+        return;
+    }
+
+    let context = Context {
+        rax: regs.rax as u64,
+        rbx: regs.rbx as u64,
+        rcx: regs.rcx as u64,
+        rdx: regs.rdx as u64,
+        rsi: regs.rsi as u64,
+        rdi: regs.rdi as u64,
+        rbp: regs.rbp as u64,
+        r8: regs.r8 as u64,
+        r9: regs.r9 as u64,
+        r10: regs.r10 as u64,
+        r11: regs.r11 as u64,
+        r12: regs.r12 as u64,
+        r13: regs.r13 as u64,
+        r14: regs.r14 as u64,
+        r15: regs.r15 as u64,
+        rip: stack_frame.instruction_pointer.as_u64(),
+        rsp: stack_frame.stack_pointer.as_u64(),
+        rflags: stack_frame.cpu_flags.bits(),
+    };
+
+    interrupts::without_interrupts(|| *SYSCALL_CONTEXT.lock() = Some(context));
+}
+
+/// Duplicates the running process from inside a syscall. The child shares
+/// the parent's memory copy-on-write and returns 0 from the syscall.
+#[must_use]
+pub fn fork() -> Option<Pid> {
+    interrupts::without_interrupts(|| {
+        let mut current = CURRENT.lock();
+        let parent = current.as_mut().expect("no process is running");
+
+        let context = SYSCALL_CONTEXT.lock().expect("fork outside of a syscall");
+
+        let child = Process {
+            pid: Pid::new(),
+            address_space: parent.address_space.fork()?,
+            context: Context { rax: 0, ..context },
+        };
+
+        let pid = child.pid;
+
+        log!("process {} forked {pid}", parent.pid);
+
+        READY.lock().push_back(child);
+
+        Some(pid)
+    })
+}
+
+/// Suspends the running process from inside a syscall, which returns 0 once
+/// `duration` has passed and [`run`] gets back to it.
+pub fn sleep(duration: Duration) -> ! {
+    assert!(is_running(), "no process is running");
+
+    let deadline = Instant::now().checked_add(duration).unwrap_or(Instant::MAX);
+
+    interrupts::without_interrupts(|| {
+        let context = SYSCALL_CONTEXT.lock().expect("sleep outside of a syscall");
+
+        let mut current = CURRENT.lock();
+        let process = current.as_mut().expect("no process is running");
+
+        process.context = Context { rax: 0, ..context };
+
+        *STOP.lock() = Some(Stop::Sleeping(deadline));
+    });
+
+    unsafe { return_to_kernel(KERNEL_RSP.load(Ordering::SeqCst)) }
+}
+
+/// Ends the running process and returns to whoever called [`run`].
+pub fn exit(code: usize) -> ! {
+    leave(ExitStatus::Exited(code))
+}
+
+/// Ends the running process after it did something it was not allowed to.
+pub fn kill() -> ! {
+    leave(ExitStatus::Killed)
+}
+
+fn leave(status: ExitStatus) -> ! {
+    assert!(is_running(), "no process is running");
+
+    interrupts::without_interrupts(|| *STOP.lock() = Some(Stop::Ended(status)));
+
+    unsafe { return_to_kernel(KERNEL_RSP.load(Ordering::SeqCst)) }
+}
+
+/// Saves the callee-saved registers and the kernel stack pointer, then drops
+/// to ring 3 with the registers in `context`.
+#[naked]
+unsafe extern "sysv64" fn enter_user(
+    _context: *const Context,
+    _saved_rsp: *mut u64,
+    _code: u64,
+    _data: u64,
+) {
+    unsafe {
+        naked_asm!(
+            "push rbp",
+            "push rbx",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "pushfq",
+            "mov [rsi], rsp",
+            "push rcx",                   // ss
+            "push qword ptr [rdi + 128]", // rsp
+            "push qword ptr [rdi + 136]", // rflags
+            "push rdx",                   // cs
+            "push qword ptr [rdi + 120]", // rip
+            "mov rax, [rdi]",
+            "mov rbx, [rdi + 8]",
+            "mov rcx, [rdi + 16]",
+            "mov rdx, [rdi + 24]",
+            "mov rsi, [rdi + 32]",
+            "mov rbp, [rdi + 48]",
+            "mov r8, [rdi + 56]",
+            "mov r9, [rdi + 64]",
+            "mov r10, [rdi + 72]",
+            "mov r11, [rdi + 80]",
+            "mov r12, [rdi + 88]",
+            "mov r13, [rdi + 96]",
+            "mov r14, [rdi + 104]",
+            "mov r15, [rdi + 112]",
+            "mov rdi, [rdi + 40]",
+            "iretq",
+        );
+    }
+}
+
+/// Unwinds to the frame saved by [`enter_user`], which then returns normally.
+#[naked]
+unsafe extern "sysv64" fn return_to_kernel(_rsp: u64) -> ! {
+    unsafe {
+        naked_asm!(
+            "mov rsp, rdi",
+            "popfq",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbx",
+            "pop rbp",
+            "ret",
+        );
+    }
+}
@@ -0,0 +1,427 @@
+use super::{
+    acpi::madt,
+    apic, cpu, gdt, idt,
+    memory::{
+        address_space,
+        frame::{self, FRAME_SIZE},
+        phys_to_virt,
+        stack::KernelStack,
+    },
+    thread, time, timer,
+};
+use crate::log;
+use alloc::{format, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr,
+};
+
+const STACK_SIZE: u64 = 4096 * 16;
+
+const EFER_LONG_MODE_ENABLE: u32 = 1 << 8;
+const EFER_NO_EXECUTE_ENABLE: u32 = 1 << 11;
+
+// Startup IPIs start a CPU in real mode at a page below 1 MiB, and the
+// trampoline runs with paging enabled before it can load a 64-bit CR3.
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+const PAGE_TABLE_LIMIT: u64 = 0x1_0000_0000;
+
+// Copied to a low page, entered in real mode, and left in long mode on the
+// stack in `smp_trampoline_stack`, calling `smp_trampoline_entry` with
+// `smp_trampoline_cpu`. The fields at the end are patched before each start.
+global_asm!(
+    r#"
+    .pushsection .rodata.smp_trampoline, "a"
+    .global smp_trampoline_start
+    .global smp_trampoline_end
+    .global smp_trampoline_long_mode
+    .global smp_trampoline_gdt
+    .global smp_trampoline_gdt_pointer
+    .global smp_trampoline_long_mode_pointer
+    .global smp_trampoline_cr3
+    .global smp_trampoline_efer
+    .global smp_trampoline_stack
+    .global smp_trampoline_cpu
+    .global smp_trampoline_entry
+
+    .code16
+smp_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    lgdtl (smp_trampoline_gdt_pointer - smp_trampoline_start)
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov (smp_trampoline_cr3 - smp_trampoline_start), %eax
+    mov %eax, %cr3
+    mov $0xC0000080, %ecx
+    rdmsr
+    or (smp_trampoline_efer - smp_trampoline_start), %eax
+    wrmsr
+    mov %cr0, %eax
+    and $0x9FFFFFFF, %eax
+    or $0x80000001, %eax
+    mov %eax, %cr0
+    ljmpl *(smp_trampoline_long_mode_pointer - smp_trampoline_start)
+
+    .code64
+smp_trampoline_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov smp_trampoline_stack(%rip), %rsp
+    mov smp_trampoline_cpu(%rip), %rdi
+    mov smp_trampoline_entry(%rip), %rax
+    call *%rax
+    ud2
+
+    .balign 8
+smp_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+smp_trampoline_gdt_pointer:
+    .word 23
+    .long 0
+smp_trampoline_long_mode_pointer:
+    .long 0
+    .word 8
+smp_trampoline_cr3:
+    .long 0
+smp_trampoline_efer:
+    .long 0
+    .balign 8
+smp_trampoline_stack:
+    .quad 0
+smp_trampoline_cpu:
+    .quad 0
+smp_trampoline_entry:
+    .quad 0
+smp_trampoline_end:
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_long_mode: u8;
+    static smp_trampoline_gdt: u8;
+    static smp_trampoline_gdt_pointer: u8;
+    static smp_trampoline_long_mode_pointer: u8;
+    static smp_trampoline_cr3: u8;
+    static smp_trampoline_efer: u8;
+    static smp_trampoline_stack: u8;
+    static smp_trampoline_cpu: u8;
+    static smp_trampoline_entry: u8;
+}
+
+/// State owned by one CPU.
+#[derive(Debug)]
+pub struct PerCpu {
+    pub index: usize,
+    pub apic_id: u32,
+    online: AtomicBool,
+    stack: Option<KernelStack>,
+}
+
+impl PerCpu {
+    #[must_use]
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+}
+
+/// Processors beyond this many are left offline.
+pub const MAX_CPUS: usize = 64;
+
+static CPUS: OnceCell<Vec<PerCpu>> = OnceCell::uninit();
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// The data block of the calling CPU.
+#[must_use]
+pub fn current() -> Option<&'static PerCpu> {
+    let cpus = CPUS.get()?;
+
+    apic::id().map_or_else(
+        || cpus.first(),
+        |apic_id| cpus.iter().find(|cpu| cpu.apic_id == apic_id),
+    )
+}
+
+/// Every CPU the kernel knows about, online or not.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.get().into_iter().flatten()
+}
+
+#[must_use]
+pub fn online_count() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Starts every enabled application processor listed in the MADT, one at a
+/// time, and parks it until it is given threads to run.
+pub fn init() {
+    let bsp = apic::id();
+
+    let processors = madt::parse()
+        .map(|madt| madt.processors)
+        .unwrap_or_default();
+
+    let mut cpus = Vec::from([PerCpu {
+        index: 0,
+        apic_id: bsp.unwrap_or(0),
+        online: AtomicBool::new(true),
+        stack: None,
+    }]);
+
+    if let Some(bsp) = bsp {
+        for processor in processors.iter().filter(|p| p.enabled) {
+            let apic_id = u32::from(processor.apic_id);
+
+            if apic_id == bsp {
+                continue;
+            }
+
+            if cpus.len() == MAX_CPUS {
+                log!("only the first {MAX_CPUS} cpus are used");
+
+                break;
+            }
+
+            let index = cpus.len();
+            let name = format!("cpu {index}").leak();
+
+            let stack = KernelStack::new(name, STACK_SIZE).expect("failed to allocate cpu stack");
+
+            cpus.push(PerCpu {
+                index,
+                apic_id,
+                online: AtomicBool::new(false),
+                stack: Some(stack),
+            });
+        }
+    }
+
+    let cpus = CPUS.get_or_init(|| cpus);
+
+    if cpus.len() > 1 {
+        let Some(trampoline) = Trampoline::new() else {
+            log!("smp unavailable, no low memory for the trampoline");
+
+            return;
+        };
+
+        for cpu in &cpus[1..] {
+            start(cpu, &trampoline);
+        }
+
+        // a CPU that missed its startup IPI may still run the trampoline
+        if cpus.iter().all(PerCpu::is_online) {
+            trampoline.free();
+        }
+    }
+
+    log!("{} of {} cpus online", online_count(), cpus.len());
+}
+
+fn start(cpu: &'static PerCpu, trampoline: &Trampoline) {
+    let stack = cpu.stack.as_ref().unwrap();
+
+    unsafe { trampoline.patch(&raw const smp_trampoline_stack, stack.top().as_u64()) };
+    unsafe { trampoline.patch(&raw const smp_trampoline_cpu, core::ptr::from_ref(cpu)) };
+    unsafe { trampoline.patch(&raw const smp_trampoline_entry, ap_main as usize) };
+
+    apic::send_init(cpu.apic_id);
+    time::nanowait(10_000_000);
+
+    for _ in 0..2 {
+        apic::send_startup(cpu.apic_id, trampoline.vector());
+
+        if wait_online(cpu, 200_000) {
+            return;
+        }
+    }
+
+    if !wait_online(cpu, 1_000_000_000) {
+        log!("cpu {} (apic id {}) did not start", cpu.index, cpu.apic_id);
+    }
+}
+
+fn wait_online(cpu: &PerCpu, nanoseconds: u64) -> bool {
+    const STEP: u64 = 100_000;
+
+    for _ in 0..nanoseconds.div_ceil(STEP) {
+        if cpu.is_online() {
+            return true;
+        }
+
+        time::nanowait(STEP);
+    }
+
+    cpu.is_online()
+}
+
+extern "C" fn ap_main(cpu: &'static PerCpu) -> ! {
+    unsafe { address_space::activate_kernel() };
+
+    gdt::init_ap(cpu.index);
+    idt::init_ap();
+    cpu::init_ap();
+    apic::init_ap();
+    timer::init_ap();
+
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    cpu.online.store(true, Ordering::SeqCst);
+
+    log!("cpu {} online (apic id {})", cpu.index, cpu.apic_id);
+
+    thread::run_ap()
+}
+
+/// Halts the calling CPU until there is something to do.
+pub fn idle() -> ! {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// A copy of the trampoline in low memory, along with page tables that map
+/// the kernel and identity map the trampoline itself.
+struct Trampoline {
+    page: PhysFrame,
+    tables: Vec<PhysFrame>,
+}
+
+impl Trampoline {
+    fn new() -> Option<Self> {
+        let page = frame::allocate_below(PhysAddr::new(TRAMPOLINE_LIMIT))?;
+
+        let Some(tables) = Self::identity_map(page) else {
+            frame::deallocate(page);
+
+            return None;
+        };
+
+        let trampoline = Self { page, tables };
+
+        let start = &raw const smp_trampoline_start;
+        let size = offset(&raw const smp_trampoline_end);
+
+        unsafe { core::ptr::copy_nonoverlapping(start, trampoline.ptr(0), size) };
+
+        let base = page.start_address().as_u64();
+
+        let efer = if cpu::has_nx() {
+            EFER_LONG_MODE_ENABLE | EFER_NO_EXECUTE_ENABLE
+        } else {
+            EFER_LONG_MODE_ENABLE
+        };
+
+        let gdt = low_u32(base + offset(&raw const smp_trampoline_gdt) as u64);
+        let long_mode = low_u32(base + offset(&raw const smp_trampoline_long_mode) as u64);
+        let cr3 = low_u32(trampoline.tables[0].start_address().as_u64());
+
+        let gdt_pointer = offset(&raw const smp_trampoline_gdt_pointer);
+
+        unsafe { trampoline.write(gdt_pointer + 2, gdt) };
+        unsafe { trampoline.patch(&raw const smp_trampoline_long_mode_pointer, long_mode) };
+        unsafe { trampoline.patch(&raw const smp_trampoline_cr3, cr3) };
+        unsafe { trampoline.patch(&raw const smp_trampoline_efer, efer) };
+
+        Some(trampoline)
+    }
+
+    /// Builds a copy of the kernel's level 4 table below 4 GiB, followed by
+    /// the lower level tables that identity map `page` through its first
+    /// entry. Whatever the kernel has in that entry is left out of the copy;
+    /// the AP only runs from there until it jumps to the kernel's own tables.
+    fn identity_map(page: PhysFrame) -> Option<Vec<PhysFrame>> {
+        let kernel = unsafe { table(address_space::kernel_level_4_frame()) };
+
+        let mut tables = Vec::from([frame::allocate_below(PhysAddr::new(PAGE_TABLE_LIMIT))?]);
+
+        for _ in 0..3 {
+            let Some(frame) = frame::allocate_zeroed() else {
+                tables.into_iter().for_each(frame::deallocate);
+
+                return None;
+            };
+
+            tables.push(frame);
+        }
+
+        unsafe { table(tables[0]) }.clone_from(kernel);
+
+        let addr = page.start_address().as_u64();
+        let indices = [addr >> 39, addr >> 30, addr >> 21, addr >> 12].map(|i| i % 512);
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        for ((&frame, &next), index) in tables
+            .iter()
+            .zip(tables[1..].iter().chain([&page]))
+            .zip(indices)
+        {
+            let table = unsafe { table(frame) };
+
+            #[allow(clippy::cast_possible_truncation)]
+            table[index as usize].set_frame(next, flags);
+        }
+
+        Some(tables)
+    }
+
+    fn vector(&self) -> u8 {
+        u8::try_from(self.page.start_address().as_u64() / FRAME_SIZE).unwrap()
+    }
+
+    fn ptr(&self, offset: usize) -> *mut u8 {
+        let base = phys_to_virt(self.page.start_address());
+
+        (base + offset as u64).as_mut_ptr()
+    }
+
+    /// # Safety
+    ///
+    /// `offset` must leave room for a `T` in the trampoline.
+    unsafe fn write<T>(&self, offset: usize, value: T) {
+        unsafe { self.ptr(offset).cast::<T>().write_unaligned(value) };
+    }
+
+    /// # Safety
+    ///
+    /// `label` must be a trampoline field that holds a `T`.
+    unsafe fn patch<T>(&self, label: *const u8, value: T) {
+        unsafe { self.write(offset(label), value) };
+    }
+
+    fn free(self) {
+        for frame in self.tables.into_iter().chain([self.page]) {
+            frame::deallocate(frame);
+        }
+    }
+}
+
+fn offset(label: *const u8) -> usize {
+    label as usize - (&raw const smp_trampoline_start) as usize
+}
+
+fn low_u32(value: u64) -> u32 {
+    u32::try_from(value).expect("trampoline address above 4 GiB")
+}
+
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
@@ -17,6 +17,26 @@ pub fn dispatcher(n: usize, arg1: usize, _arg2: usize, _arg3: usize, _arg4: usiz
                 service::sleep(seconds);
                 0
             }
+            Syscall::Exit => {
+                log!("syscall: exit {arg1}");
+
+                service::exit(arg1);
+            }
+            Syscall::Fork => {
+                log!("syscall: fork");
+
+                service::fork()
+            }
+            Syscall::Shutdown => {
+                log!("syscall: shutdown");
+
+                service::shutdown();
+            }
+            Syscall::Reboot => {
+                log!("syscall: reboot");
+
+                service::reboot();
+            }
         },
         Err(()) => panic!("invalid syscall number {}", n),
     }
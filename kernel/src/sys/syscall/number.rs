@@ -2,6 +2,10 @@
 #[repr(usize)]
 pub enum Syscall {
     Sleep = 1,
+    Exit = 2,
+    Fork = 3,
+    Shutdown = 4,
+    Reboot = 5,
 }
 
 impl TryFrom<usize> for Syscall {
@@ -10,6 +14,10 @@ impl TryFrom<usize> for Syscall {
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         match value {
             1 => Ok(Self::Sleep),
+            2 => Ok(Self::Exit),
+            3 => Ok(Self::Fork),
+            4 => Ok(Self::Shutdown),
+            5 => Ok(Self::Reboot),
             _ => Err(()),
         }
     }
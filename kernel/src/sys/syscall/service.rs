@@ -1,3 +1,25 @@
 pub fn sleep(seconds: f64) {
+    if crate::sys::process::is_running() {
+        let duration = crate::sys::clock::Duration::try_from_secs_f64(seconds).unwrap_or_default();
+
+        crate::sys::process::sleep(duration);
+    }
+
     crate::sys::clock::sleep(seconds);
 }
+
+pub fn exit(code: usize) -> ! {
+    crate::sys::process::exit(code);
+}
+
+pub fn fork() -> usize {
+    crate::sys::process::fork().map_or(usize::MAX, |pid| pid.0)
+}
+
+pub fn shutdown() -> ! {
+    crate::sys::power::shutdown();
+}
+
+pub fn reboot() -> ! {
+    crate::sys::power::reboot();
+}
@@ -1,13 +1,31 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{spawner::Spawner, Priority, Task};
+use crate::{
+    log,
+    sys::{apic, smp, thread},
+};
+use alloc::{sync::Arc, task::Wake, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Waker},
+};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use spin::Mutex;
 use x86_64::instructions::interrupts;
 
+const READY_QUEUE_CAPACITY: usize = 100;
+
+/// The idle mask has one bit per worker.
+const MAX_WORKERS: usize = u64::BITS as usize;
+
+/// The executor running on every CPU, once [`Executor::run`] is called.
+static STARTED: OnceCell<Arc<Shared>> = OnceCell::uninit();
+
+/// Runs tasks on every online CPU. Each CPU works through its own ready
+/// queues and steals from the others when they run dry, so tasks migrate to
+/// wherever there is time to poll them.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    shared: Arc<Shared>,
 }
 
 impl Default for Executor {
@@ -19,89 +37,278 @@ impl Default for Executor {
 impl Executor {
     #[must_use]
     pub fn new() -> Self {
+        let apic_ids: Vec<u32> = smp::cpus()
+            .take(MAX_WORKERS)
+            .map(|cpu| cpu.apic_id)
+            .collect();
+
+        let workers = apic_ids.len().max(1);
+
         Self {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            shared: Arc::new(Shared {
+                queues: (0..workers).map(|_| ReadyQueues::new()).collect(),
+                apic_ids,
+                idle: AtomicU64::new(0),
+                spawn_queue: Arc::new(SegQueue::new()),
+            }),
+        }
+    }
+
+    /// A handle that spawns tasks onto this executor from anywhere.
+    #[must_use]
+    pub fn spawner(&self) -> Spawner {
+        Spawner::new(self.shared.spawn_queue.clone())
+    }
+
+    pub fn spawn(&self, task: Task) {
+        self.shared.spawn(task);
+    }
+
+    /// Turns the calling thread into a worker of this executor, and starts a
+    /// worker thread on every other online CPU.
+    pub fn run(&self) -> ! {
+        STARTED
+            .try_init_once(|| self.shared.clone())
+            .expect("an executor is already running");
+
+        let cpu = self.shared.current_cpu().unwrap_or(0);
+
+        let others = smp::cpus()
+            .take(self.shared.queues.len())
+            .filter(|other| other.index != cpu && other.is_online());
+
+        for other in others {
+            let shared = self.shared.clone();
+            let index = other.index;
+
+            thread::spawn_on(index, "executor", move || shared.work(index));
         }
+
+        log!("executor running on {} cpus", smp::online_count());
+
+        self.shared.work(cpu)
+    }
+}
+
+struct Shared {
+    /// Ready queues of each worker, indexed like [`smp::cpus`].
+    queues: Vec<ReadyQueues>,
+    apic_ids: Vec<u32>,
+    /// One bit per worker halted waiting for work.
+    idle: AtomicU64,
+    spawn_queue: Arc<SegQueue<Task>>,
+}
+
+impl Shared {
+    /// The worker index of the calling CPU, if it has one.
+    fn current_cpu(&self) -> Option<usize> {
+        smp::current()
+            .map(|cpu| cpu.index)
+            .filter(|&index| index < self.queues.len())
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
+    fn spawn(self: &Arc<Self>, task: Task) {
+        let cpu = self.current_cpu().unwrap_or(0);
 
-        assert!(
-            self.tasks.insert(task.id, task).is_none(),
-            "task with same ID already in tasks"
-        );
+        let runnable = Arc::new(Runnable {
+            priority: task.priority(),
+            state: AtomicU8::new(QUEUED),
+            cpu: AtomicUsize::new(cpu),
+            task: Mutex::new(Some(task)),
+            shared: self.clone(),
+        });
 
-        self.task_queue.push(task_id).expect("queue full");
+        self.schedule(runnable, cpu);
     }
 
-    pub fn run(&mut self) -> ! {
+    /// Queues a task on `cpu` and makes sure some worker will get to it.
+    fn schedule(&self, runnable: Arc<Runnable>, cpu: usize) {
+        self.queues[cpu].get(runnable.priority).push(runnable);
+
+        let current = self.current_cpu();
+        let idle = self.idle.load(Ordering::SeqCst);
+
+        let target = if current == Some(cpu) {
+            // a busy worker lets an idle one steal the task, while an idle
+            // one was woken by whatever queued it and finds it itself
+            if idle & (1 << cpu) != 0 {
+                return;
+            }
+
+            let others = idle & !(1 << cpu);
+
+            if others == 0 {
+                return;
+            }
+
+            others.trailing_zeros() as usize
+        } else if idle & (1 << cpu) != 0 {
+            cpu
+        } else {
+            return;
+        };
+
+        if let Some(&apic_id) = self.apic_ids.get(target) {
+            apic::send_wakeup(apic_id);
+        }
+    }
+
+    fn work(self: &Arc<Self>, cpu: usize) -> ! {
         loop {
-            self.run_ready_tasks();
-            self.sleep_if_idle();
+            self.spawn_queued_tasks();
+
+            if self.run_ready_tasks(cpu) == 0 && !self.steal(cpu) {
+                self.sleep_if_idle(cpu);
+            }
+        }
+    }
+
+    fn spawn_queued_tasks(self: &Arc<Self>) {
+        while let Some(task) = self.spawn_queue.pop() {
+            self.spawn(task);
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+    /// Runs one round: each class, highest first, gets up to its budget of
+    /// polls, so a busy class delays the ones above it by at most one budget.
+    fn run_ready_tasks(&self, cpu: usize) -> usize {
+        let mut polled = 0;
 
-        while let Some(task_id) = task_queue.pop() {
-            let Some(task) = tasks.get_mut(&task_id) else {
-                continue;
-            };
+        for priority in Priority::ALL {
+            let queue = self.queues[cpu].get(priority);
+
+            for _ in 0..priority.budget() {
+                let Some(runnable) = queue.pop() else {
+                    break;
+                };
+
+                runnable.run(cpu);
+
+                polled += 1;
+            }
+        }
+
+        polled
+    }
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
+    /// Moves one ready task from another worker's queues to this one's,
+    /// taking the highest class first.
+    fn steal(&self, cpu: usize) -> bool {
+        let workers = self.queues.len();
 
-            let mut context = Context::from_waker(waker);
+        for priority in Priority::ALL {
+            for victim in (1..workers).map(|offset| (cpu + offset) % workers) {
+                if let Some(runnable) = self.queues[victim].get(priority).pop() {
+                    self.queues[cpu].get(priority).push(runnable);
 
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    return true;
                 }
-                Poll::Pending => {}
             }
         }
+
+        false
     }
 
-    fn sleep_if_idle(&self) {
+    fn sleep_if_idle(&self, cpu: usize) {
         interrupts::disable();
 
-        if self.task_queue.is_empty() {
-            interrupts::enable_and_hlt();
+        // advertised before checking, so that anything queued from here on
+        // sends a wakeup
+        self.idle.fetch_or(1 << cpu, Ordering::SeqCst);
+
+        if self.queues.iter().all(ReadyQueues::is_empty) && self.spawn_queue.is_empty() {
+            thread::wait_for_interrupt();
         } else {
             interrupts::enable();
         }
+
+        self.idle.fetch_and(!(1 << cpu), Ordering::SeqCst);
     }
 }
 
-struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+// `Runnable::state` values; `RUNNING` becomes `WOKEN` when woken mid-poll
+const IDLE: u8 = 0;
+const QUEUED: u8 = 1;
+const RUNNING: u8 = 2;
+const WOKEN: u8 = 3;
+const DONE: u8 = 4;
+
+/// A task as seen by the workers, doubling as its waker. The state keeps it
+/// in at most one queue and on at most one CPU at a time.
+struct Runnable {
+    priority: Priority,
+    state: AtomicU8,
+    /// The worker that last polled the task.
+    cpu: AtomicUsize,
+    task: Mutex<Option<Task>>,
+    shared: Arc<Shared>,
 }
 
-impl TaskWaker {
-    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(Self {
-            task_id,
-            task_queue,
-        }))
+impl Runnable {
+    fn run(self: Arc<Self>, cpu: usize) {
+        self.cpu.store(cpu, Ordering::Relaxed);
+        self.state.store(RUNNING, Ordering::SeqCst);
+
+        let waker = Waker::from(self.clone());
+        let mut context = Context::from_waker(&waker);
+
+        let mut task = self.task.lock();
+
+        let Some(poll) = task.as_mut().map(|task| task.poll(&mut context)) else {
+            return;
+        };
+
+        if poll.is_ready() {
+            self.state.store(DONE, Ordering::SeqCst);
+            task.take();
+
+            return;
+        }
+
+        drop(task);
+
+        if self
+            .state
+            .compare_exchange(RUNNING, IDLE, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            // woken while being polled
+            self.state.store(QUEUED, Ordering::SeqCst);
+            self.shared.clone().schedule(self, cpu);
+        }
     }
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+    fn wake_task(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::SeqCst);
+
+        loop {
+            let next = match state {
+                IDLE => QUEUED,
+                RUNNING => WOKEN,
+                _ => return,
+            };
+
+            match self
+                .state
+                .compare_exchange(state, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+
+        if state == IDLE {
+            let cpu = self
+                .shared
+                .current_cpu()
+                .unwrap_or_else(|| self.cpu.load(Ordering::Relaxed));
+
+            self.shared.schedule(self.clone(), cpu);
+        }
     }
 }
 
-impl Wake for TaskWaker {
+impl Wake for Runnable {
     fn wake(self: Arc<Self>) {
         self.wake_task();
     }
@@ -110,3 +317,55 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+/// One ready queue per priority class.
+struct ReadyQueues {
+    queues: [ReadyQueue; Priority::ALL.len()],
+}
+
+impl ReadyQueues {
+    fn new() -> Self {
+        Self {
+            queues: core::array::from_fn(|_| ReadyQueue::new()),
+        }
+    }
+
+    const fn get(&self, priority: Priority) -> &ReadyQueue {
+        &self.queues[priority.index()]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(ReadyQueue::is_empty)
+    }
+}
+
+/// Tasks waiting to be polled. Pushing never allocates until the fixed-size
+/// queue fills up, after which wakeups spill into an unbounded one rather
+/// than being lost. Other workers pop from it too when stealing.
+struct ReadyQueue {
+    fast: ArrayQueue<Arc<Runnable>>,
+    overflow: SegQueue<Arc<Runnable>>,
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        Self {
+            fast: ArrayQueue::new(READY_QUEUE_CAPACITY),
+            overflow: SegQueue::new(),
+        }
+    }
+
+    fn push(&self, runnable: Arc<Runnable>) {
+        if let Err(runnable) = self.fast.push(runnable) {
+            self.overflow.push(runnable);
+        }
+    }
+
+    fn pop(&self) -> Option<Arc<Runnable>> {
+        self.fast.pop().or_else(|| self.overflow.pop())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.fast.is_empty() && self.overflow.is_empty()
+    }
+}
@@ -1,35 +1,143 @@
-use alloc::boxed::Box;
+use crate::{
+    log,
+    sys::clock::{Duration, Instant},
+};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{
+    fmt,
     future::Future,
     pin::Pin,
     sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
 pub mod executor;
 pub mod keyboard;
+pub mod spawner;
+pub mod sync;
+pub mod timer;
+
+/// Every live task, for diagnostics.
+static TASKS: Mutex<BTreeMap<TaskId, Arc<TaskInfo>>> = Mutex::new(BTreeMap::new());
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    info: Arc<TaskInfo>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self::named("task", future)
+    }
+
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self::with_priority(name, Priority::default(), future)
+    }
+
+    pub fn with_priority(
+        name: &'static str,
+        priority: Priority,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        let id = TaskId::new();
+
+        let info = Arc::new(TaskInfo {
+            name,
+            priority,
+            spawned: Instant::now(),
+            polls: AtomicU64::new(0),
+            poll_nanoseconds: AtomicU64::new(0),
+        });
+
+        interrupts::without_interrupts(|| TASKS.lock().insert(id, info.clone()));
+
         Self {
-            id: TaskId::new(),
+            id,
+            info,
             future: Box::pin(future),
         }
     }
 
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.info.name
+    }
+
+    #[must_use]
+    pub fn priority(&self) -> Priority {
+        self.info.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let start = Instant::now();
+
+        let poll = self.future.as_mut().poll(context);
+
+        #[allow(clippy::cast_possible_truncation)]
+        let elapsed = start.elapsed().as_nanos() as u64;
+
+        self.info.polls.fetch_add(1, Ordering::Relaxed);
+        self.info
+            .poll_nanoseconds
+            .fetch_add(elapsed, Ordering::Relaxed);
+
+        poll
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| TASKS.lock().remove(&self.id));
     }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 
+/// Scheduling class of a task. The executor serves higher classes first,
+/// but gives each class a budget of polls per round so none of them starves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Work deferred from interrupt handlers.
+    BottomHalf,
+    /// Tasks a user is waiting on, such as echoing input.
+    Interactive,
+    /// Bulk work like disk or network transfers.
+    #[default]
+    Background,
+}
+
+impl Priority {
+    pub const ALL: [Self; 3] = [Self::BottomHalf, Self::Interactive, Self::Background];
+
+    /// Polls a class gets per round before lower classes have their turn.
+    #[must_use]
+    pub const fn budget(self) -> usize {
+        match self {
+            Self::BottomHalf => 64,
+            Self::Interactive => 32,
+            Self::Background => 8,
+        }
+    }
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BottomHalf => write!(f, "bottom half"),
+            Self::Interactive => write!(f, "interactive"),
+            Self::Background => write!(f, "background"),
+        }
+    }
+}
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -37,3 +145,66 @@ impl TaskId {
         Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+/// What is known about a task while it lives, shared with [`TASKS`].
+struct TaskInfo {
+    name: &'static str,
+    priority: Priority,
+    spawned: Instant,
+    polls: AtomicU64,
+    poll_nanoseconds: AtomicU64,
+}
+
+/// A snapshot of one task's statistics.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStats {
+    pub id: u64,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub age: Duration,
+    pub polls: u64,
+    pub poll_time: Duration,
+}
+
+impl fmt::Display for TaskStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "task {} {} ({}): {} polls, {} us polling, {} ms old",
+            self.id,
+            self.name,
+            self.priority,
+            self.polls,
+            self.poll_time.as_micros(),
+            self.age.as_millis()
+        )
+    }
+}
+
+#[must_use]
+pub fn stats() -> Vec<TaskStats> {
+    let tasks = interrupts::without_interrupts(|| TASKS.lock().clone());
+
+    tasks
+        .iter()
+        .map(|(id, info)| TaskStats {
+            id: id.0,
+            name: info.name,
+            priority: info.priority,
+            age: info.spawned.elapsed(),
+            polls: info.polls.load(Ordering::Relaxed),
+            poll_time: Duration::from_nanos(info.poll_nanoseconds.load(Ordering::Relaxed)),
+        })
+        .collect()
+}
+
+/// The `tasks` diagnostic: logs every live task with its statistics.
+pub fn log_tasks() {
+    let stats = stats();
+
+    for task in &stats {
+        log!("{}", task);
+    }
+
+    log!("{} tasks", stats.len());
+}
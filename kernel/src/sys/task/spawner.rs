@@ -0,0 +1,225 @@
+use super::{Priority, Task};
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::SegQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Hands new tasks to an executor, which picks them up on its next round.
+/// Spawning never blocks, so it works from interrupt handlers too.
+#[derive(Clone)]
+pub struct Spawner {
+    queue: Arc<SegQueue<Task>>,
+}
+
+impl fmt::Debug for Spawner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Spawner")
+            .field("queued", &self.queue.len())
+            .finish()
+    }
+}
+
+impl Spawner {
+    pub(super) const fn new(queue: Arc<SegQueue<Task>>) -> Self {
+        Self { queue }
+    }
+
+    /// Runs `future` as a new task and returns a handle to its output.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_named("task", future)
+    }
+
+    /// Like [`Spawner::spawn`], naming the task in [`super::stats`].
+    pub fn spawn_named<F>(&self, name: &'static str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with_priority(name, Priority::default(), future)
+    }
+
+    pub fn spawn_with_priority<F>(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        future: F,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState {
+            output: None,
+            finished: false,
+            cancelled: false,
+            taken: false,
+            task_waker: None,
+            join_waker: None,
+        }));
+
+        self.spawn_task(Task::with_priority(
+            name,
+            priority,
+            Joinable {
+                future,
+                state: state.clone(),
+            },
+        ));
+
+        JoinHandle { state }
+    }
+
+    pub fn spawn_task(&self, task: Task) {
+        self.queue.push(task);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    cancelled: bool,
+    /// Set once the handle has returned the outcome.
+    taken: bool,
+    task_waker: Option<Waker>,
+    join_waker: Option<Waker>,
+}
+
+/// Awaits the output of a task started by [`Spawner::spawn`]. Dropping the
+/// handle lets the task run on unobserved.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+impl<T> JoinHandle<T> {
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        interrupts::without_interrupts(|| self.state.lock().finished)
+    }
+
+    /// Stops the task the next time the executor gets to it. Awaiting the
+    /// handle then yields [`JoinError::Cancelled`], unless the task had
+    /// already finished.
+    pub fn cancel(&self) {
+        let waker = interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            if state.finished {
+                return None;
+            }
+
+            state.cancelled = true;
+            state.task_waker.take()
+        });
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            assert!(!state.taken, "JoinHandle polled after completion");
+
+            if let Some(output) = state.output.take() {
+                state.taken = true;
+
+                return Poll::Ready(Ok(output));
+            }
+
+            if state.finished {
+                state.taken = true;
+
+                return Poll::Ready(Err(JoinError::Cancelled));
+            }
+
+            state.join_waker = Some(cx.waker().clone());
+
+            Poll::Pending
+        })
+    }
+}
+
+/// Wraps a spawned future to report its output to the [`JoinHandle`].
+struct Joinable<F: Future> {
+    future: F,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // the future is never moved out of the pinned wrapper
+        let this = unsafe { self.get_unchecked_mut() };
+
+        let cancelled = interrupts::without_interrupts(|| {
+            let mut state = this.state.lock();
+
+            state.task_waker = Some(cx.waker().clone());
+            state.cancelled
+        });
+
+        let output = if cancelled {
+            None
+        } else {
+            let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+            match future.poll(cx) {
+                Poll::Ready(output) => Some(output),
+                Poll::Pending => return Poll::Pending,
+            }
+        };
+
+        let waker = interrupts::without_interrupts(|| {
+            let mut state = this.state.lock();
+
+            state.output = output;
+            state.finished = true;
+            state.task_waker = None;
+            state.join_waker.take()
+        });
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Poll::Ready(())
+    }
+}
@@ -0,0 +1,251 @@
+use super::semaphore::{Semaphore, TryAcquireError};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    fmt,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{task::AtomicWaker, Stream};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Creates a channel holding up to `capacity` values. Senders wait while it
+/// is full.
+#[must_use]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be non-zero");
+
+    let channel = Arc::new(Channel {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: Semaphore::new(capacity),
+        senders: AtomicUsize::new(1),
+        waker: AtomicWaker::new(),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+struct Channel<T> {
+    queue: Mutex<VecDeque<T>>,
+    /// One permit per free slot; closed when the receiver goes away.
+    capacity: Semaphore,
+    senders: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl<T> Channel<T> {
+    fn push(&self, value: T) {
+        interrupts::without_interrupts(|| self.queue.lock().push_back(value));
+
+        self.waker.wake();
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = interrupts::without_interrupts(|| self.queue.lock().pop_front())?;
+
+        self.capacity.add_permits(1);
+
+        Some(value)
+    }
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Waits for room and queues `value`, handing it back if the receiver
+    /// has been dropped.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.channel.capacity.acquire().await {
+            Ok(permit) => {
+                permit.forget();
+
+                self.channel.push(value);
+
+                Ok(())
+            }
+            Err(_) => Err(SendError(value)),
+        }
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.channel.capacity.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+
+                self.channel.push(value);
+
+                Ok(())
+            }
+            Err(TryAcquireError::NoPermits) => Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => Err(TrySendError::Closed(value)),
+        }
+    }
+
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.channel.capacity.is_closed()
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // let the receiver see that nothing more is coming
+            self.channel.waker.wake();
+        }
+    }
+}
+
+/// The receiving half of [`channel`], also usable as a [`Stream`].
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value, or `None` once every sender is gone and the
+    /// queue is drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        core::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.channel.pop() {
+            return Ok(value);
+        }
+
+        if self.channel.senders.load(Ordering::Acquire) == 0 {
+            // a sender may have queued a value just before leaving
+            return self.channel.pop().ok_or(TryRecvError::Disconnected);
+        }
+
+        Err(TryRecvError::Empty)
+    }
+
+    /// Stops further sends. Values already queued can still be received.
+    pub fn close(&mut self) {
+        self.channel.capacity.close();
+    }
+
+    fn poll_recv(&self, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.channel.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.channel.waker.register(cx.waker());
+
+        if let Some(value) = self.channel.pop() {
+            self.channel.waker.take();
+
+            return Poll::Ready(Some(value));
+        }
+
+        if self.channel.senders.load(Ordering::Acquire) == 0 {
+            // a sender may have queued a value just before leaving
+            return Poll::Ready(self.channel.pop());
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Error returned by [`Sender::send`] when the receiver is gone, holding the
+/// value that could not be sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Full(_) => write!(f, "channel full"),
+            Self::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "channel empty"),
+            Self::Disconnected => write!(f, "every sender dropped"),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Wakes tasks waiting for an event. A [`Notify::notify_one`] with nobody
+/// waiting is remembered, so the next [`Notify::notified`] completes at once.
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+struct State {
+    permit: bool,
+    next_id: u64,
+    waiters: BTreeMap<u64, Waiter>,
+}
+
+struct Waiter {
+    notified: Option<Notification>,
+    waker: Option<Waker>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+impl State {
+    fn notify_one(&mut self) -> Option<Waker> {
+        let waiter = self
+            .waiters
+            .values_mut()
+            .find(|waiter| waiter.notified.is_none());
+
+        if let Some(waiter) = waiter {
+            waiter.notified = Some(Notification::One);
+            waiter.waker.take()
+        } else {
+            self.permit = true;
+            None
+        }
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Notify").finish_non_exhaustive()
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notify {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                permit: false,
+                next_id: 0,
+                waiters: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub const fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+
+    /// Wakes the longest waiting task, or stores a permit for the next one.
+    pub fn notify_one(&self) {
+        let waker = interrupts::without_interrupts(|| self.state.lock().notify_one());
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wakes every task waiting right now, without storing a permit.
+    pub fn notify_waiters(&self) {
+        let wakers: Vec<Waker> = interrupts::without_interrupts(|| {
+            self.state
+                .lock()
+                .waiters
+                .values_mut()
+                .filter(|waiter| waiter.notified.is_none())
+                .filter_map(|waiter| {
+                    waiter.notified = Some(Notification::All);
+                    waiter.waker.take()
+                })
+                .collect()
+        });
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by [`Notify::notified`].
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let id = self.id;
+
+        let id = interrupts::without_interrupts(|| {
+            let mut state = self.notify.state.lock();
+
+            if let Some(id) = id {
+                let waiter = state.waiters.get_mut(&id).expect("waiter is queued");
+
+                if waiter.notified.is_some() {
+                    state.waiters.remove(&id);
+
+                    return None;
+                }
+
+                waiter.waker = Some(cx.waker().clone());
+
+                return Some(id);
+            }
+
+            if core::mem::take(&mut state.permit) {
+                return None;
+            }
+
+            let id = state.next_id;
+            state.next_id += 1;
+
+            state.waiters.insert(
+                id,
+                Waiter {
+                    notified: None,
+                    waker: Some(cx.waker().clone()),
+                },
+            );
+
+            Some(id)
+        });
+
+        self.id = id;
+
+        if id.is_some() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+
+        let waker = interrupts::without_interrupts(|| {
+            let mut state = self.notify.state.lock();
+
+            let waiter = state.waiters.remove(&id)?;
+
+            // pass on a notification meant for one task rather than lose it
+            if waiter.notified == Some(Notification::One) {
+                state.notify_one()
+            } else {
+                None
+            }
+        });
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
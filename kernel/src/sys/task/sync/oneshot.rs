@@ -0,0 +1,161 @@
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Creates a channel that carries a single value.
+#[must_use]
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        value: None,
+        sender_dropped: false,
+        receiver_dropped: false,
+        waker: None,
+    }));
+
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+    waker: Option<Waker>,
+}
+
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Hands `value` to the receiver, or back if it has been dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            if state.receiver_dropped {
+                return Err(value);
+            }
+
+            state.value = Some(value);
+
+            Ok(())
+        })
+    }
+
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        interrupts::without_interrupts(|| self.state.lock().receiver_dropped)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            state.sender_dropped = true;
+            state.waker.take()
+        });
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Awaits the value, failing if the sender is dropped without sending one.
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            match state.value.take() {
+                Some(value) => Ok(value),
+                None if state.sender_dropped => Err(TryRecvError::Closed),
+                None => Err(TryRecvError::Empty),
+            }
+        })
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            if let Some(value) = state.value.take() {
+                return Poll::Ready(Ok(value));
+            }
+
+            if state.sender_dropped {
+                return Poll::Ready(Err(RecvError));
+            }
+
+            state.waker = Some(cx.waker().clone());
+
+            Poll::Pending
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| self.state.lock().receiver_dropped = true);
+    }
+}
+
+/// Error returned when the sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sender dropped without sending")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "no value sent yet"),
+            Self::Closed => write!(f, "sender dropped without sending"),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use super::semaphore::{Semaphore, SemaphorePermit};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+/// Readers take one permit and writers take all of them. The semaphore
+/// serves waiters in order, so a queued writer holds back later readers.
+const MAX_READERS: usize = u32::MAX as usize;
+
+/// An awaitable reader-writer lock.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T: ?Sized> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RwLock")
+            .field(
+                "readers",
+                &(MAX_READERS - self.semaphore.available_permits()),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(MAX_READERS),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    // the future is Send whenever T is Send + Sync
+    #[allow(clippy::future_not_send)]
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let Ok(permit) = self.semaphore.acquire().await else {
+            unreachable!("rwlock semaphore is never closed");
+        };
+
+        RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    // the future is Send whenever T is Send + Sync
+    #[allow(clippy::future_not_send)]
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let Ok(permit) = self.semaphore.acquire_many(MAX_READERS).await else {
+            unreachable!("rwlock semaphore is never closed");
+        };
+
+        RwLockWriteGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore
+            .try_acquire()
+            .ok()
+            .map(|permit| RwLockReadGuard {
+                lock: self,
+                _permit: permit,
+            })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore
+            .try_acquire_many(MAX_READERS)
+            .ok()
+            .map(|permit| RwLockWriteGuard {
+                lock: self,
+                _permit: permit,
+            })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // writers cannot hold the lock while any read permit is out
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // holding every permit excludes all other guards
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
@@ -0,0 +1,320 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Counts permits that tasks wait for without spinning. Waiters are served
+/// in order, so a large request is not starved by a stream of small ones.
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    next_id: u64,
+    /// Waiters by arrival, which is also the order they are served in.
+    waiters: BTreeMap<u64, Waiter>,
+}
+
+struct Waiter {
+    permits: usize,
+    granted: bool,
+    waker: Option<Waker>,
+}
+
+impl State {
+    /// Hands free permits to waiters in order, collecting the wakers to call
+    /// once the lock is released.
+    fn grant(&mut self, wakers: &mut Vec<Waker>) {
+        for waiter in self.waiters.values_mut().filter(|waiter| !waiter.granted) {
+            if waiter.permits > self.permits {
+                break;
+            }
+
+            self.permits -= waiter.permits;
+            waiter.granted = true;
+
+            wakers.extend(waiter.waker.take());
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.available_permits())
+            .finish()
+    }
+}
+
+impl Semaphore {
+    #[must_use]
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                permits,
+                closed: false,
+                next_id: 0,
+                waiters: BTreeMap::new(),
+            }),
+        }
+    }
+
+    #[must_use]
+    pub fn available_permits(&self) -> usize {
+        interrupts::without_interrupts(|| self.state.lock().permits)
+    }
+
+    pub const fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits until `permits` are free and takes them all at once.
+    pub const fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            if state.closed {
+                return Err(TryAcquireError::Closed);
+            }
+
+            if !state.waiters.is_empty() || state.permits < permits {
+                return Err(TryAcquireError::NoPermits);
+            }
+
+            state.permits -= permits;
+
+            Ok(SemaphorePermit {
+                semaphore: self,
+                permits,
+            })
+        })
+    }
+
+    pub fn add_permits(&self, permits: usize) {
+        let mut wakers = Vec::new();
+
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            state.permits += permits;
+            state.grant(&mut wakers);
+        });
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Fails every current and future acquire. Permits already held stay
+    /// valid.
+    pub fn close(&self) {
+        let mut wakers = Vec::new();
+
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+
+            state.closed = true;
+
+            wakers.extend(
+                state
+                    .waiters
+                    .values_mut()
+                    .filter_map(|waiter| waiter.waker.take()),
+            );
+        });
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        interrupts::without_interrupts(|| self.state.lock().closed)
+    }
+}
+
+/// Future returned by [`Semaphore::acquire`] and [`Semaphore::acquire_many`].
+/// Dropping it gives up its place in the queue.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+
+        let id = interrupts::without_interrupts(|| {
+            let mut guard = semaphore.state.lock();
+            let state = &mut *guard;
+
+            if let Some(id) = self.id {
+                let waiter = state.waiters.get_mut(&id).expect("waiter is queued");
+
+                if waiter.granted {
+                    state.waiters.remove(&id);
+
+                    return Ok(None);
+                }
+
+                if state.closed {
+                    state.waiters.remove(&id);
+
+                    return Err(AcquireError);
+                }
+
+                waiter.waker = Some(cx.waker().clone());
+
+                return Ok(Some(id));
+            }
+
+            if state.closed {
+                return Err(AcquireError);
+            }
+
+            if state.waiters.is_empty() && state.permits >= permits {
+                state.permits -= permits;
+
+                return Ok(None);
+            }
+
+            let id = state.next_id;
+            state.next_id += 1;
+
+            state.waiters.insert(
+                id,
+                Waiter {
+                    permits,
+                    granted: false,
+                    waker: Some(cx.waker().clone()),
+                },
+            );
+
+            Ok(Some(id))
+        });
+
+        match id {
+            Ok(None) => {
+                self.id = None;
+
+                Poll::Ready(Ok(SemaphorePermit { semaphore, permits }))
+            }
+            Ok(Some(id)) => {
+                self.id = Some(id);
+
+                Poll::Pending
+            }
+            Err(error) => {
+                self.id = None;
+
+                Poll::Ready(Err(error))
+            }
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+
+        let mut wakers = Vec::new();
+
+        interrupts::without_interrupts(|| {
+            let mut state = self.semaphore.state.lock();
+
+            if let Some(waiter) = state.waiters.remove(&id) {
+                if waiter.granted {
+                    state.permits += waiter.permits;
+                }
+
+                // waiters behind this one may fit now
+                state.grant(&mut wakers);
+            }
+        });
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Permits held until dropped.
+#[must_use = "permits are released as soon as they are dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits taken for good instead of releasing them.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// Error returned by [`Acquire`] once the semaphore has been closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "semaphore closed")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+impl fmt::Display for TryAcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "semaphore closed"),
+            Self::NoPermits => write!(f, "no permits available"),
+        }
+    }
+}
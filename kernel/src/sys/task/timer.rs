@@ -0,0 +1,178 @@
+use crate::sys::{
+    clock::{Duration, Instant},
+    timer::{self, TimerId},
+};
+use alloc::boxed::Box;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::{Stream, StreamExt};
+
+/// Waits until `duration` has passed without holding up other tasks.
+#[must_use]
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now().checked_add(duration).unwrap_or(Instant::MAX))
+}
+
+#[must_use]
+pub const fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        registered: None,
+    }
+}
+
+/// Future returned by [`sleep`] and [`sleep_until`]. The timer interrupt
+/// wakes the task once the deadline passes.
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    registered: Option<(TimerId, Waker)>,
+}
+
+impl Sleep {
+    #[must_use]
+    pub const fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Moves the deadline, e.g. to reuse the future for the next period.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if let Some((timer, _)) = self.registered.take() {
+            timer::cancel(timer);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.cancel();
+
+            return Poll::Ready(());
+        }
+
+        let registered = self
+            .registered
+            .as_ref()
+            .is_some_and(|(_, waker)| waker.will_wake(cx.waker()));
+
+        if !registered {
+            self.cancel();
+
+            let timer = timer::wake_at(self.deadline, cx.waker().clone());
+
+            self.registered = Some((timer, cx.waker().clone()));
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Yields once every `period`, starting one period from now.
+#[must_use]
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must be non-zero");
+
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+/// Stream returned by [`interval`]. Ticks that were missed because the task
+/// ran late are skipped rather than delivered in a burst.
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// Waits for the next tick and returns its scheduled time.
+    pub async fn tick(&mut self) -> Instant {
+        self.next().await.unwrap()
+    }
+
+    #[must_use]
+    pub const fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let tick = self.sleep.deadline();
+        let now = Instant::now();
+
+        let mut next = tick.checked_add(self.period).unwrap_or(Instant::MAX);
+
+        if next <= now {
+            next = now.checked_add(self.period).unwrap_or(Instant::MAX);
+        }
+
+        self.sleep.reset(next);
+
+        Poll::Ready(Some(tick))
+    }
+}
+
+/// Error returned by [`timeout`] when the deadline passes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+/// Runs `future` for at most `duration`, dropping it if it has not finished
+/// by then.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(duration),
+    }
+}
+
+/// Future returned by [`timeout`].
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        Pin::new(&mut self.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
@@ -0,0 +1,645 @@
+use super::{
+    apic,
+    clock::{Duration, Instant},
+    memory::stack::KernelStack,
+    smp, timer,
+};
+use crate::log;
+use alloc::{
+    boxed::Box, collections::BTreeMap, collections::VecDeque, format, sync::Arc, vec::Vec,
+};
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::naked_asm,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+use spin::{Mutex, MutexGuard};
+use x86_64::{instructions::interrupts, structures::idt::InterruptStackFrame, PrivilegeLevel};
+
+const STACK_SIZE: u64 = 4096 * 16;
+
+/// How long a thread runs before the timer interrupt hands the CPU to the
+/// next ready one.
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+/// Interrupts are off when a new thread starts; [`thread_main`] turns them on.
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/// One scheduler per CPU, indexed like [`smp::cpus`]. Threads stay on the
+/// CPU they were spawned on.
+static SCHEDULERS: OnceCell<Vec<Mutex<Scheduler>>> = OnceCell::uninit();
+
+/// Every thread that has not been reaped yet.
+static THREADS: Mutex<BTreeMap<ThreadId, Arc<Thread>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// `Thread::state` values, changed under the lock of the thread's scheduler
+const READY: u8 = 0;
+const RUNNING: u8 = 1;
+const BLOCKED: u8 = 2;
+const DEAD: u8 = 3;
+
+// how far the timer of a [`sleep_until`] got, changed under the same lock
+const TIMER_PENDING: u8 = 0;
+const TIMER_FIRED: u8 = 1;
+/// Fired and made the blocked thread ready.
+const TIMER_WOKE: u8 = 2;
+
+/// A kernel thread with its own stack and saved registers.
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    cpu: usize,
+    state: AtomicU8,
+    /// Set by [`Thread::unblock`] on a thread that is not blocked, so that
+    /// its next [`block`] returns at once.
+    unblocked: AtomicBool,
+    /// Stack pointer saved by [`switch`] while the thread is not running.
+    rsp: AtomicU64,
+    /// `None` for threads adopted from a boot stack.
+    stack: Mutex<Option<KernelStack>>,
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("cpu", &self.cpu)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Thread {
+    fn new(name: &'static str, cpu: usize, rsp: u64, stack: Option<KernelStack>) -> Arc<Self> {
+        let thread = Arc::new(Self {
+            id: ThreadId::new(),
+            name,
+            cpu,
+            state: AtomicU8::new(RUNNING),
+            unblocked: AtomicBool::new(false),
+            rsp: AtomicU64::new(rsp),
+            stack: Mutex::new(stack),
+        });
+
+        interrupts::without_interrupts(|| THREADS.lock().insert(thread.id, thread.clone()));
+
+        thread
+    }
+
+    /// A new thread that calls `f` on a fresh stack once scheduled.
+    fn with_entry(name: &'static str, cpu: usize, f: Box<dyn FnOnce() + Send>) -> Arc<Self> {
+        let stack = KernelStack::new(name, STACK_SIZE).expect("failed to allocate thread stack");
+
+        // the frame `switch` pops, returning into `thread_start` with the
+        // stack aligned for its call
+        let top = stack.top().as_u64() - 16;
+        let entry = Box::into_raw(Box::new(f));
+
+        let frame: [u64; 8] = [
+            INITIAL_RFLAGS,
+            0,            // r15
+            0,            // r14
+            0,            // r13
+            entry as u64, // r12
+            0,            // rbx
+            0,            // rbp
+            thread_start as usize as u64,
+        ];
+
+        let rsp = top - 8 * frame.len() as u64;
+
+        unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()) };
+
+        let thread = Self::new(name, cpu, rsp, Some(stack));
+        thread.state.store(READY, Ordering::SeqCst);
+
+        thread
+    }
+
+    #[must_use]
+    pub const fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[must_use]
+    pub const fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == DEAD
+    }
+
+    /// Makes a thread waiting in [`block`] runnable again. If it is not
+    /// blocked, its next call to [`block`] returns immediately instead.
+    pub fn unblock(self: &Arc<Self>) {
+        let Some(scheduler) = SCHEDULERS.get().and_then(|s| s.get(self.cpu)) else {
+            return;
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut scheduler = scheduler.lock();
+
+            if self.state.load(Ordering::SeqCst) == BLOCKED {
+                self.state.store(READY, Ordering::SeqCst);
+
+                scheduler.make_ready(self.clone());
+            } else {
+                self.unblocked.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+
+    /// The [`sleep_until`] timer: wakes the thread if it is blocked, but
+    /// unlike [`Thread::unblock`] leaves nothing behind for a later [`block`].
+    fn wake_sleeper(self: &Arc<Self>, timer_state: &AtomicU8) {
+        let Some(scheduler) = SCHEDULERS.get().and_then(|s| s.get(self.cpu)) else {
+            return;
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut scheduler = scheduler.lock();
+
+            if self.state.load(Ordering::SeqCst) == BLOCKED {
+                self.state.store(READY, Ordering::SeqCst);
+                timer_state.store(TIMER_WOKE, Ordering::SeqCst);
+
+                scheduler.make_ready(self.clone());
+            } else {
+                timer_state.store(TIMER_FIRED, Ordering::SeqCst);
+            }
+        });
+    }
+}
+
+struct Scheduler {
+    cpu: usize,
+    current: Arc<Thread>,
+    /// Runs when nothing else is ready; never in `ready` itself.
+    idle: Arc<Thread>,
+    ready: VecDeque<Arc<Thread>>,
+    slice_end: Instant,
+    slice_timer: bool,
+    /// A thread that exited, to be reaped by the one switched to.
+    dead: Option<Arc<Thread>>,
+}
+
+impl Scheduler {
+    fn make_ready(&mut self, thread: Arc<Thread>) {
+        self.ready.push_back(thread);
+
+        if Some(self.cpu) == current_cpu() {
+            self.arm_slice_timer();
+        } else if let Some(cpu) = smp::cpus().nth(self.cpu) {
+            // the CPU preempts its current thread on the wakeup
+            apic::send_wakeup(cpu.apic_id);
+        }
+    }
+
+    /// Makes sure the timer interrupts this CPU when the current slice ends.
+    fn arm_slice_timer(&mut self) {
+        if !self.slice_timer {
+            self.slice_timer = true;
+
+            // a thread that ran on alone past its slice gets a fresh one
+            let now = Instant::now();
+
+            if self.slice_end <= now {
+                self.slice_end = now + TIME_SLICE;
+            }
+
+            timer::call_at(self.slice_end, || {});
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        Arc::ptr_eq(&self.current, &self.idle)
+    }
+}
+
+/// Sets up a scheduler on every CPU. The calling context becomes the "main"
+/// thread of the boot CPU, and each application processor's boot stack
+/// becomes its idle thread.
+pub fn init() {
+    let cpus = smp::cpus().count().max(1);
+
+    let schedulers = (0..cpus)
+        .map(|cpu| {
+            let (current, idle) = if cpu == 0 {
+                let idle = Thread::with_entry("cpu 0 idle", 0, Box::new(|| idle_loop()));
+
+                (Thread::new("main", 0, 0, None), idle)
+            } else {
+                let idle = Thread::new(format!("cpu {cpu} idle").leak(), cpu, 0, None);
+
+                (idle.clone(), idle)
+            };
+
+            Mutex::new(Scheduler {
+                cpu,
+                current,
+                idle,
+                ready: VecDeque::new(),
+                slice_end: Instant::now() + TIME_SLICE,
+                slice_timer: false,
+                dead: None,
+            })
+        })
+        .collect();
+
+    SCHEDULERS.init_once(|| schedulers);
+
+    log!(
+        "threads initialized, {} ms time slice",
+        TIME_SLICE.as_millis()
+    );
+}
+
+fn current_cpu() -> Option<usize> {
+    smp::current().map(|cpu| cpu.index)
+}
+
+fn scheduler() -> Option<&'static Mutex<Scheduler>> {
+    SCHEDULERS.get()?.get(current_cpu().unwrap_or(0))
+}
+
+/// Starts a thread running `f` on the calling CPU.
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> Arc<Thread> {
+    spawn_on(current_cpu().unwrap_or(0), name, f)
+}
+
+/// Starts a thread running `f` on `cpu`, where it stays.
+pub fn spawn_on(cpu: usize, name: &'static str, f: impl FnOnce() + Send + 'static) -> Arc<Thread> {
+    let scheduler = SCHEDULERS
+        .get()
+        .expect("threads not initialized")
+        .get(cpu)
+        .expect("no such cpu");
+
+    let thread = Thread::with_entry(name, cpu, Box::new(f));
+
+    interrupts::without_interrupts(|| scheduler.lock().make_ready(thread.clone()));
+
+    thread
+}
+
+/// The thread running on the calling CPU.
+#[must_use]
+pub fn current() -> Option<Arc<Thread>> {
+    let scheduler = scheduler()?;
+
+    Some(interrupts::without_interrupts(|| {
+        scheduler.lock().current.clone()
+    }))
+}
+
+/// Lets the next ready thread on this CPU run, if there is one.
+pub fn yield_now() {
+    let Some(scheduler) = scheduler() else {
+        return;
+    };
+
+    interrupts::without_interrupts(|| reschedule(scheduler.lock()));
+}
+
+/// Suspends the calling thread until [`Thread::unblock`] is called on it.
+pub fn block() {
+    let Some(scheduler) = scheduler() else {
+        return;
+    };
+
+    interrupts::without_interrupts(|| {
+        let scheduler = scheduler.lock();
+
+        if scheduler.current.unblocked.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        scheduler.current.state.store(BLOCKED, Ordering::SeqCst);
+
+        reschedule(scheduler);
+    });
+}
+
+/// Ends the calling thread. Its stack is freed by the next thread to run.
+pub fn exit() -> ! {
+    let scheduler = scheduler().expect("threads not initialized");
+
+    interrupts::disable();
+
+    let scheduler = scheduler.lock();
+
+    scheduler.current.state.store(DEAD, Ordering::SeqCst);
+
+    reschedule(scheduler);
+
+    unreachable!("dead thread was scheduled");
+}
+
+/// Blocks the calling thread for `duration`, letting others use the CPU.
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now().checked_add(duration).unwrap_or(Instant::MAX));
+}
+
+/// Blocks the calling thread until `deadline`. An unblock from elsewhere in
+/// the meantime does not end the sleep, but is kept for the next [`block`].
+pub fn sleep_until(deadline: Instant) {
+    let thread = current().expect("threads not initialized");
+    let timer_state = Arc::new(AtomicU8::new(TIMER_PENDING));
+
+    let timer = {
+        let thread = thread.clone();
+        let timer_state = timer_state.clone();
+
+        timer::call_at(deadline, move || thread.wake_sleeper(&timer_state))
+    };
+
+    let mut unblocked = false;
+
+    while Instant::now() < deadline {
+        unblocked |= block_sleeper(&timer_state);
+    }
+
+    // the timer was added on this CPU, where the thread stays, so once it is
+    // cancelled it cannot fire anymore
+    timer::cancel(timer);
+
+    if unblocked {
+        thread.unblocked.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Blocks for [`sleep_until`] unless its timer already fired. Returns whether
+/// something other than the timer unblocked the thread.
+fn block_sleeper(timer_state: &AtomicU8) -> bool {
+    let Some(scheduler) = scheduler() else {
+        return false;
+    };
+
+    interrupts::without_interrupts(|| {
+        let scheduler = scheduler.lock();
+
+        if scheduler.current.unblocked.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+
+        if timer_state.load(Ordering::SeqCst) != TIMER_PENDING {
+            return false;
+        }
+
+        scheduler.current.state.store(BLOCKED, Ordering::SeqCst);
+
+        reschedule(scheduler);
+
+        timer_state.load(Ordering::SeqCst) != TIMER_WOKE
+    })
+}
+
+#[must_use]
+pub fn is_initialized() -> bool {
+    SCHEDULERS.get().is_some()
+}
+
+/// Runs another ready thread, or halts until the next interrupt if there is
+/// none. Must be called with interrupts disabled; returns with them enabled.
+pub fn wait_for_interrupt() {
+    let ready = scheduler().is_some_and(|scheduler| !scheduler.lock().ready.is_empty());
+
+    if ready {
+        yield_now();
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Where application processors go once they are up. The CPU idles until it
+/// is given threads to run.
+pub fn run_ap() -> ! {
+    idle_loop()
+}
+
+fn idle_loop() -> ! {
+    loop {
+        interrupts::disable();
+
+        wait_for_interrupt();
+    }
+}
+
+/// Switches to the next ready thread if the current one used up its slice or
+/// is the idle thread. Called at the end of the timer and wakeup interrupts.
+pub fn preempt(stack_frame: &InterruptStackFrame) {
+    // user mode interrupts run on the per-CPU kernel stack from the TSS,
+    // which cannot be switched away from
+    if stack_frame.code_segment.rpl() != PrivilegeLevel::Ring0 {
+        return;
+    }
+
+    let Some(scheduler) = scheduler() else {
+        return;
+    };
+
+    let mut scheduler = scheduler.lock();
+
+    if scheduler.ready.is_empty() {
+        return;
+    }
+
+    if scheduler.is_idle() || Instant::now() >= scheduler.slice_end {
+        reschedule(scheduler);
+    } else {
+        scheduler.arm_slice_timer();
+    }
+}
+
+/// Hands the CPU to the next ready thread. A current thread that is still
+/// running goes to the back of the queue, and keeps the CPU if nothing else
+/// is ready. Must be called with interrupts disabled.
+fn reschedule(mut scheduler: MutexGuard<Scheduler>) {
+    let runnable = scheduler.current.state.load(Ordering::SeqCst) == RUNNING;
+
+    let next = match scheduler.ready.pop_front() {
+        Some(next) => next,
+        None if runnable => return,
+        None => scheduler.idle.clone(),
+    };
+
+    // the idle thread has nothing to switch to
+    if Arc::ptr_eq(&next, &scheduler.current) {
+        next.state.store(RUNNING, Ordering::SeqCst);
+
+        return;
+    }
+
+    let previous = core::mem::replace(&mut scheduler.current, next.clone());
+
+    if runnable {
+        previous.state.store(READY, Ordering::SeqCst);
+
+        if !Arc::ptr_eq(&previous, &scheduler.idle) {
+            scheduler.ready.push_back(previous.clone());
+        }
+    } else if previous.state.load(Ordering::SeqCst) == DEAD {
+        scheduler.dead = Some(previous.clone());
+    }
+
+    next.state.store(RUNNING, Ordering::SeqCst);
+
+    scheduler.slice_end = Instant::now() + TIME_SLICE;
+    scheduler.slice_timer = false;
+
+    if !scheduler.ready.is_empty() {
+        scheduler.arm_slice_timer();
+    }
+
+    let previous_rsp = previous.rsp.as_ptr();
+    let next_rsp = next.rsp.load(Ordering::SeqCst);
+
+    // THREADS keeps both threads alive across the switch
+    drop(previous);
+    drop(next);
+    drop(scheduler);
+
+    unsafe { switch(previous_rsp, next_rsp) };
+
+    finish_switch();
+}
+
+/// Frees a thread that exited on the way to the current one.
+fn finish_switch() {
+    let Some(scheduler) = scheduler() else {
+        return;
+    };
+
+    let Some(dead) = scheduler.lock().dead.take() else {
+        return;
+    };
+
+    THREADS.lock().remove(&dead.id);
+
+    let stack = dead.stack.lock().take();
+
+    if let Some(stack) = stack {
+        // the dead thread will never run on its stack again
+        unsafe { stack.free() };
+    }
+}
+
+/// Saves the callee-saved registers and flags of the current thread on its
+/// stack, stores its stack pointer in `previous_rsp`, and resumes the thread
+/// whose stack pointer is `next_rsp`.
+#[naked]
+unsafe extern "sysv64" fn switch(_previous_rsp: *mut u64, _next_rsp: u64) {
+    unsafe {
+        naked_asm!(
+            "push rbp",
+            "push rbx",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "pushfq",
+            "mov [rdi], rsp",
+            "mov rsp, rsi",
+            "popfq",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbx",
+            "pop rbp",
+            "ret",
+        );
+    }
+}
+
+/// First code of a new thread, entered from [`switch`] with the entry closure
+/// in `r12`.
+#[naked]
+unsafe extern "sysv64" fn thread_start() -> ! {
+    unsafe {
+        naked_asm!(
+            "mov rdi, r12",
+            "call {}",
+            "ud2",
+            sym thread_main,
+        );
+    }
+}
+
+extern "sysv64" fn thread_main(entry: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+
+    finish_switch();
+
+    interrupts::enable();
+
+    entry();
+
+    exit()
+}
+
+/// A snapshot of one thread, for diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub cpu: usize,
+    pub state: &'static str,
+}
+
+impl fmt::Display for ThreadInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "thread {} {} on cpu {}: {}",
+            self.id, self.name, self.cpu, self.state
+        )
+    }
+}
+
+#[must_use]
+pub fn threads() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| {
+        THREADS
+            .lock()
+            .values()
+            .map(|thread| ThreadInfo {
+                id: thread.id,
+                name: thread.name,
+                cpu: thread.cpu,
+                state: match thread.state.load(Ordering::SeqCst) {
+                    READY => "ready",
+                    RUNNING => "running",
+                    BLOCKED => "blocked",
+                    _ => "dead",
+                },
+            })
+            .collect()
+    })
+}
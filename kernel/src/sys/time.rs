@@ -1,6 +1,6 @@
 use crate::{
     log,
-    sys::{self, cmos::Cmos, idt::Irq},
+    sys::{self, clocksource, cmos::Cmos, idt::Irq},
 };
 use core::{
     hint::spin_loop,
@@ -14,8 +14,7 @@ const PIT_DIVIDER: usize = 1 << 16;
 const PIT_INTERVAL: f64 = (PIT_DIVIDER as f64) / PIT_FREQUENCY;
 
 static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
-static LAST_RTC_UPDATE: AtomicUsize = AtomicUsize::new(0);
-static CLOCKS_PER_NANOSECOND: AtomicU64 = AtomicU64::new(0);
+static LAST_RTC_UPDATE: AtomicU64 = AtomicU64::new(0);
 
 pub fn ticks() -> usize {
     PIT_TICKS.load(Ordering::Relaxed)
@@ -26,7 +25,18 @@ pub const fn time_between_ticks() -> f64 {
     PIT_INTERVAL
 }
 
-pub fn last_rtc_update() -> usize {
+/// Nanoseconds since the PIT started, counted in whole ticks.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn pit_nanoseconds() -> u64 {
+    // PIT_FREQUENCY is 3_579_545 / 3 Hz
+    let ticks = ticks() as u128 * PIT_DIVIDER as u128;
+
+    (ticks * 3_000_000_000 / 3_579_545) as u64
+}
+
+/// Monotonic nanoseconds at the last RTC update.
+pub fn last_rtc_update() -> u64 {
     LAST_RTC_UPDATE.load(Ordering::Relaxed)
 }
 
@@ -38,23 +48,16 @@ pub fn halt() {
     }
 }
 
-fn rdtsc() -> u64 {
-    unsafe { core::arch::x86_64::_mm_lfence() };
-    unsafe { core::arch::x86_64::_rdtsc() }
-}
-
 pub fn sleep(seconds: f64) {
-    let start = sys::clock::uptime();
-    #[allow(clippy::while_float)]
-    while sys::clock::uptime() - start < seconds {
-        halt();
-    }
+    sys::clock::sleep(seconds);
 }
 
+/// Spins for at least `nanoseconds`, timed by the TSC, so it also works with
+/// interrupts disabled.
 pub fn nanowait(nanoseconds: u64) {
-    let start = rdtsc();
-    let delta = nanoseconds * CLOCKS_PER_NANOSECOND.load(Ordering::Relaxed);
-    while rdtsc() - start < delta {
+    let start = clocksource::rdtsc();
+    let delta = clocksource::nanoseconds_to_cycles(nanoseconds);
+    while clocksource::rdtsc() - start < delta {
         spin_loop();
     }
 }
@@ -78,7 +81,7 @@ pub fn pit_interrupt_handler() {
 }
 
 pub fn rtc_interrupt_handler() {
-    LAST_RTC_UPDATE.store(ticks(), Ordering::Relaxed);
+    LAST_RTC_UPDATE.store(clocksource::nanoseconds(), Ordering::Relaxed);
     Cmos::new().notify_end_of_interrupt();
 }
 
@@ -92,13 +95,4 @@ pub fn init() {
 
     sys::idt::set_irq_handler(Irq::Rtc as u8, rtc_interrupt_handler);
     Cmos::new().enable_update_interrupt();
-
-    let calibration_time = 250_000;
-    let a = rdtsc();
-    #[allow(clippy::cast_precision_loss)]
-    sleep(calibration_time as f64 / 1e6);
-    let b = rdtsc();
-    CLOCKS_PER_NANOSECOND.store((b - a) / calibration_time, Ordering::Relaxed);
-
-    log!("clock initialized");
 }
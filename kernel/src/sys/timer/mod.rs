@@ -0,0 +1,274 @@
+use super::{
+    apic,
+    clock::{Duration, Instant},
+    clocksource::{self, ClockSource},
+    idt::{self, Irq},
+    smp::{self, MAX_CPUS},
+    time,
+};
+use crate::log;
+use alloc::{boxed::Box, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    task::Waker,
+};
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use wheel::Wheel;
+use x86_64::instructions::interrupts;
+
+pub mod wheel;
+
+/// Length of one wheel tick; timers fire on the first tick boundary at or
+/// after their deadline.
+pub const TICK_NANOSECONDS: u64 = 1_000_000;
+
+const CALIBRATION_NANOSECONDS: u64 = 10_000_000;
+
+/// One wheel per CPU, created on first use. A timer lives in the wheel of
+/// the CPU that added it, and only that CPU's hardware timer is armed for
+/// it, so the other CPUs can stay halted.
+static WHEELS: [OnceCell<Mutex<Wheel<Action>>>; MAX_CPUS] =
+    [const { OnceCell::uninit() }; MAX_CPUS];
+
+static BACKEND: OnceCell<Backend> = OnceCell::uninit();
+
+/// Local APIC timer counts per second in one-shot mode.
+static APIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// What raises the interrupt that runs due timers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    /// The local APIC timer fires at a TSC value.
+    TscDeadline,
+    /// The local APIC timer counts down to the next deadline.
+    ApicOneShot,
+    /// Every PIT tick checks the wheel, for machines without an APIC.
+    PitTick,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId {
+    cpu: usize,
+    id: u64,
+}
+
+impl TimerId {
+    fn new(cpu: usize) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            cpu,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+fn current_cpu() -> usize {
+    smp::current().map_or(0, |cpu| cpu.index)
+}
+
+fn wheel(cpu: usize) -> &'static Mutex<Wheel<Action>> {
+    WHEELS[cpu].get_or_init(|| Mutex::new(Wheel::new()))
+}
+
+enum Action {
+    Call(Box<dyn FnOnce() + Send>),
+    Wake(Waker),
+}
+
+impl Action {
+    fn fire(self) {
+        match self {
+            Self::Call(f) => f(),
+            Self::Wake(waker) => waker.wake(),
+        }
+    }
+}
+
+/// Picks the hardware timer. With the local APIC timer and a clock source
+/// other than the PIT, the periodic PIT interrupt is masked, so CPUs are only
+/// interrupted when a timer is due.
+pub fn init() {
+    let backend = if apic::is_enabled() {
+        let tsc_deadline = CpuId::new()
+            .get_feature_info()
+            .is_some_and(|info| info.has_tsc_deadline());
+
+        if tsc_deadline {
+            Backend::TscDeadline
+        } else {
+            calibrate_apic_timer();
+
+            Backend::ApicOneShot
+        }
+    } else {
+        idt::set_irq_handler(Irq::Timer as u8, pit_tick);
+
+        Backend::PitTick
+    };
+
+    BACKEND.init_once(|| backend);
+
+    init_ap();
+
+    let tickless = backend != Backend::PitTick && clocksource::current() != ClockSource::Pit;
+
+    if tickless {
+        idt::set_irq_mask(Irq::Timer as u8);
+    }
+
+    log!(
+        "timer initialized, {backend:?}{}",
+        if tickless { ", tickless" } else { "" }
+    );
+}
+
+/// Sets up the local timer of the calling CPU.
+pub fn init_ap() {
+    match BACKEND.get() {
+        Some(Backend::TscDeadline) => apic::init_timer(true),
+        Some(Backend::ApicOneShot) => apic::init_timer(false),
+        _ => {}
+    }
+}
+
+fn calibrate_apic_timer() {
+    apic::init_timer(false);
+    apic::set_timer_count(u32::MAX);
+
+    time::nanowait(CALIBRATION_NANOSECONDS);
+
+    let counted = u64::from(u32::MAX - apic::timer_count());
+
+    apic::set_timer_count(0);
+
+    APIC_TIMER_FREQUENCY.store(
+        counted * 1_000_000_000 / CALIBRATION_NANOSECONDS,
+        Ordering::Relaxed,
+    );
+}
+
+/// Whether timers run from the PIT interrupt rather than the local APIC
+/// timer.
+#[must_use]
+pub fn is_pit_tick() -> bool {
+    BACKEND.get() == Some(&Backend::PitTick)
+}
+
+/// Calls `f` from the timer interrupt once `deadline` has passed.
+pub fn call_at(deadline: Instant, f: impl FnOnce() + Send + 'static) -> TimerId {
+    add(deadline, Action::Call(Box::new(f)))
+}
+
+pub fn call_after(delay: Duration, f: impl FnOnce() + Send + 'static) -> TimerId {
+    call_at(Instant::now() + delay, f)
+}
+
+/// Wakes `waker` once `deadline` has passed.
+#[must_use]
+pub fn wake_at(deadline: Instant, waker: Waker) -> TimerId {
+    add(deadline, Action::Wake(waker))
+}
+
+/// Stops a timer if it has not fired yet. The CPU it was added on may still
+/// take one interrupt for it.
+pub fn cancel(id: TimerId) {
+    if let Some(wheel) = WHEELS[id.cpu].get() {
+        interrupts::without_interrupts(|| drop(wheel.lock().remove(id.id)));
+    }
+}
+
+/// Timers waiting to fire on any CPU.
+#[must_use]
+pub fn pending() -> usize {
+    WHEELS
+        .iter()
+        .filter_map(OnceCell::get)
+        .map(|wheel| interrupts::without_interrupts(|| wheel.lock().len()))
+        .sum()
+}
+
+fn add(deadline: Instant, action: Action) -> TimerId {
+    let when = deadline.as_nanos().div_ceil(TICK_NANOSECONDS);
+
+    let (id, expired) = interrupts::without_interrupts(|| {
+        let id = TimerId::new(current_cpu());
+
+        let mut wheel = wheel(id.cpu).lock();
+
+        let result = wheel.insert(id.id, when, action);
+
+        arm(&wheel);
+
+        (id, result.err())
+    });
+
+    if let Some(action) = expired {
+        action.fire();
+    }
+
+    id
+}
+
+/// Fires every due timer of the calling CPU and arms its hardware for the
+/// next one. Called from the timer interrupt.
+pub fn interrupt() {
+    let now = Instant::now().as_nanos() / TICK_NANOSECONDS;
+
+    let mut expired = Vec::new();
+
+    interrupts::without_interrupts(|| {
+        let mut wheel = wheel(current_cpu()).lock();
+
+        wheel.advance(now, &mut expired);
+
+        arm(&wheel);
+    });
+
+    for action in expired {
+        action.fire();
+    }
+}
+
+fn pit_tick() {
+    time::pit_interrupt_handler();
+
+    interrupt();
+}
+
+/// Programs the timer of the calling CPU for the next slot of the wheel, or
+/// stops it if nothing is pending.
+fn arm<T>(wheel: &Wheel<T>) {
+    let Some(&backend) = BACKEND.get() else {
+        return;
+    };
+
+    let delay = wheel.next_expiration().map(|tick| {
+        let deadline = Instant::from_nanos(tick.saturating_mul(TICK_NANOSECONDS));
+
+        deadline.duration_since(Instant::now())
+    });
+
+    #[allow(clippy::cast_possible_truncation)]
+    let nanoseconds = delay.map(|delay| delay.as_nanos().min(u128::from(u64::MAX)) as u64);
+
+    match (backend, nanoseconds) {
+        (Backend::TscDeadline, Some(nanoseconds)) => {
+            let cycles = clocksource::nanoseconds_to_cycles(nanoseconds);
+
+            apic::set_tsc_deadline(clocksource::rdtsc().saturating_add(cycles.max(1)));
+        }
+        (Backend::TscDeadline, None) => apic::set_tsc_deadline(0),
+        (Backend::ApicOneShot, Some(nanoseconds)) => {
+            let frequency = APIC_TIMER_FREQUENCY.load(Ordering::Relaxed);
+            let count = u128::from(nanoseconds) * u128::from(frequency) / 1_000_000_000;
+
+            // a deadline beyond the counter's range fires early and rearms
+            apic::set_timer_count(u32::try_from(count).unwrap_or(u32::MAX).max(1));
+        }
+        (Backend::ApicOneShot, None) => apic::set_timer_count(0),
+        (Backend::PitTick, _) => {}
+    }
+}
@@ -0,0 +1,187 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+const LEVELS: usize = 6;
+const SLOTS: usize = 64;
+const SLOT_BITS: u32 = SLOTS.trailing_zeros();
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+
+/// How far ahead the top level reaches. Later entries are parked in it and
+/// cascade around it until they come within range.
+pub const MAX_TICKS: u64 = 1 << (SLOT_BITS as usize * LEVELS);
+
+struct Entry<T> {
+    id: u64,
+    when: u64,
+    value: T,
+}
+
+struct Level<T> {
+    /// One bit per slot holding at least one entry.
+    occupied: u64,
+    slots: [Vec<Entry<T>>; SLOTS],
+}
+
+impl<T> Level<T> {
+    fn new() -> Self {
+        Self {
+            occupied: 0,
+            slots: core::array::from_fn(|_| Vec::new()),
+        }
+    }
+}
+
+/// A hierarchical timer wheel counting in abstract ticks.
+///
+/// Level `n` has 64 slots of `64^n` ticks each, so an entry is placed by the
+/// highest bits in which its deadline differs from the current time, and
+/// moves down a level each time its slot comes up until it lands in level 0
+/// and expires.
+pub struct Wheel<T> {
+    elapsed: u64,
+    levels: [Level<T>; LEVELS],
+    locations: BTreeMap<u64, (usize, usize)>,
+}
+
+impl<T> Default for Wheel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Wheel<T> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            elapsed: 0,
+            levels: core::array::from_fn(|_| Level::new()),
+            locations: BTreeMap::new(),
+        }
+    }
+
+    /// The tick up to which entries have expired.
+    #[must_use]
+    pub const fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    /// Adds an entry due at tick `when`, handing `value` back if that has
+    /// already passed.
+    pub fn insert(&mut self, id: u64, when: u64, value: T) -> Result<(), T> {
+        if when <= self.elapsed {
+            return Err(value);
+        }
+
+        let level = level_for(self.elapsed, when);
+        let slot = slot_for(when, level);
+
+        self.levels[level].slots[slot].push(Entry { id, when, value });
+        self.levels[level].occupied |= 1 << slot;
+        self.locations.insert(id, (level, slot));
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<T> {
+        let (level, slot) = self.locations.remove(&id)?;
+
+        let level = &mut self.levels[level];
+        let entries = &mut level.slots[slot];
+
+        let index = entries.iter().position(|entry| entry.id == id)?;
+        let entry = entries.swap_remove(index);
+
+        if entries.is_empty() {
+            level.occupied &= !(1 << slot);
+        }
+
+        Some(entry.value)
+    }
+
+    /// The tick at which the earliest slot comes up. Entries in it may still
+    /// be later, when it belongs to a higher level.
+    #[must_use]
+    pub fn next_expiration(&self) -> Option<u64> {
+        self.next_slot().map(|(_, _, deadline)| deadline)
+    }
+
+    /// Moves time forward to `now`, collecting the values of every entry due
+    /// by then into `expired` and moving the rest down the levels.
+    pub fn advance(&mut self, now: u64, expired: &mut Vec<T>) {
+        while let Some((level, slot, deadline)) = self.next_slot() {
+            if deadline > now {
+                break;
+            }
+
+            self.elapsed = deadline;
+
+            let entries = core::mem::take(&mut self.levels[level].slots[slot]);
+            self.levels[level].occupied &= !(1 << slot);
+
+            for entry in entries {
+                self.locations.remove(&entry.id);
+
+                if let Err(value) = self.insert(entry.id, entry.when, entry.value) {
+                    expired.push(value);
+                }
+            }
+        }
+
+        self.elapsed = self.elapsed.max(now);
+    }
+
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .filter(|(_, level)| level.occupied != 0)
+            .map(|(index, level)| {
+                let slot_range = slot_range(index);
+                let level_range = slot_range * SLOTS as u64;
+
+                #[allow(clippy::cast_possible_truncation)]
+                let now_slot = ((self.elapsed / slot_range) & SLOT_MASK) as u32;
+
+                let zeros = level.occupied.rotate_right(now_slot).trailing_zeros();
+                let slot = (zeros + now_slot) as usize % SLOTS;
+
+                let level_start = self.elapsed & !(level_range - 1);
+                let mut deadline = level_start + slot as u64 * slot_range;
+
+                // only the top level wraps around to slots behind the current
+                // time
+                if deadline <= self.elapsed {
+                    deadline += level_range;
+                }
+
+                (index, slot, deadline)
+            })
+            .min_by_key(|&(index, _, deadline)| (deadline, index))
+    }
+}
+
+fn level_for(elapsed: u64, when: u64) -> usize {
+    let masked = ((elapsed ^ when) | SLOT_MASK).min(MAX_TICKS - 1);
+
+    let significant = u64::BITS - 1 - masked.leading_zeros();
+
+    (significant / SLOT_BITS) as usize
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn slot_for(when: u64, level: usize) -> usize {
+    ((when >> (SLOT_BITS as usize * level)) & SLOT_MASK) as usize
+}
+
+const fn slot_range(level: usize) -> u64 {
+    1 << (SLOT_BITS as usize * level)
+}